@@ -0,0 +1,466 @@
+
+//! Textual assembly for purple programs.
+//!
+//! A program is a sequence of functions, each introduced by `func <n>:` and followed by
+//! instructions separated by newlines or `;`.  Everything after a `#` on a line is a comment.
+//! Both are taken out before anything else is read and there is no quoting, so registry names
+//! and `load_value` values cannot contain `;` or `#`.
+//!
+//! ```text
+//! func 0:
+//!     load_value s0 7; push_param s0
+//!     load_func s1 f1; call s1
+//!     load_from_return s0
+//!     return s0
+//! func 1: pop_param s0; load_from_exec s0 double; return s0
+//! ```
+//!
+//! A function header may carry a name, as in `func 1 double:`, and the `name` directive names a
+//! symbol or label of the current function, as in `name s0 total`.  Names and the position of
+//! every instruction are recorded in the assembly's debug info.
+//!
+//! Symbols are written `s<n>`, labels `l<n>`, functions `f<n>` and natives `n<n>`.  The value of `load_value` is
+//! the rest of the statement and is parsed with `FromStr`.  Closure bearing instructions name a
+//! native function which is looked up in a `Registry`.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::data::*;
+use crate::debug::{DebugInfo, Span};
+use crate::error::ParseError;
+use crate::registry::Registry;
+
+pub struct Assembly<T : Clone, Env> {
+    pub func_defs : HashMap<Func, Vec<Instr<T, Env>>>,
+    /// Registry name of the native function carried by the instruction at (function, index).
+    pub native_names : HashMap<(Func, usize), String>,
+    /// Names given in the source and the span of every instruction.
+    pub debug_info : DebugInfo,
+}
+
+pub fn parse<T, Env>( input : &str, registry : &Registry<T, Env> ) -> Result<Assembly<T, Env>, ParseError>
+    where T : Clone + FromStr + 'static, Env : 'static {
+
+    let mut func_defs : HashMap<Func, Vec<Instr<T, Env>>> = HashMap::new();
+    let mut native_names : HashMap<(Func, usize), String> = HashMap::new();
+    let mut debug_info = DebugInfo::new();
+    let mut current_function : Option<Func> = None;
+
+    for mut statement in statements(input) {
+        if statement.at_end() {
+            continue;
+        }
+
+        if statement.peek_word() == Some("func") {
+            statement.word("func")?;
+            let (column, func) = statement.number("function number")?;
+            if !statement.at_end() && statement.peek_word() != Some(":") {
+                let (_, name) = statement.word("function name")?;
+                debug_info.func_names.insert(Func(func), name.to_string());
+            }
+            statement.expect(":")?;
+
+            if func_defs.insert(Func(func), vec![]).is_some() {
+                return Err(ParseError::RedefinitionOfFunction { line: statement.line, column, func });
+            }
+            current_function = Some(Func(func));
+
+            if statement.at_end() {
+                continue;
+            }
+        }
+
+        let func = match current_function {
+            Some(func) => func,
+            None => return Err(ParseError::InstructionOutsideFunction { line: statement.line, column: statement.column() }),
+        };
+
+        if statement.peek_word() == Some("name") {
+            statement.word("name")?;
+            let (column, id) = statement.word("symbol or label")?;
+            let (_, name) = statement.word("name")?;
+            match (id.get(..1), id.get(1..).map(|n| n.parse::<usize>())) {
+                (Some("s"), Some(Ok(n))) => { debug_info.symbol_names.insert((func, Symbol(n)), name.to_string()); },
+                (Some("l"), Some(Ok(n))) => { debug_info.label_names.insert((func, Label(n)), name.to_string()); },
+                _ => return Err(ParseError::UnexpectedToken { line: statement.line, column, found: id.to_string(), expected: "symbol or label" }),
+            }
+            statement.end()?;
+            continue;
+        }
+
+        let span = Span { line: statement.line, column: statement.column() };
+        let (instr, native_name) = parse_instr(&mut statement, registry)?;
+        statement.end()?;
+
+        // NOTE:  current_function is always inserted into func_defs when it is set.
+        let body = func_defs.get_mut(&func).unwrap();
+        if let Some(name) = native_name {
+            native_names.insert((func, body.len()), name);
+        }
+        debug_info.spans.insert((func, body.len()), span);
+        body.push(instr);
+    }
+
+    Ok(Assembly { func_defs, native_names, debug_info })
+}
+
+fn parse_instr<T, Env>( statement : &mut Statement, registry : &Registry<T, Env> ) -> Result<(Instr<T, Env>, Option<String>), ParseError>
+    where T : Clone + FromStr + 'static, Env : 'static {
+
+    let (column, name) = statement.word("instruction")?;
+    let line = statement.line;
+
+    macro_rules! native {
+        ($lookup:ident) => {{
+            let (column, name) = statement.word("native function name")?;
+            match registry.$lookup(name) {
+                Some(f) => (f, name.to_string()),
+                None => return Err(ParseError::UnknownNative { line, column, name: name.to_string() }),
+            }
+        }};
+    }
+
+    let result = match name {
+        "label" => (Instr::Label(statement.label()?), None),
+        "jump" => (Instr::Jump(statement.label()?), None),
+        "branch_on_true" => {
+            let label = statement.label()?;
+            let (f, name) = native!(branch);
+            (Instr::BranchOnTrue(label, f), Some(name))
+        },
+        "return" => (Instr::Return(statement.symbol()?), None),
+        "load_value" => {
+            let sym = statement.symbol()?;
+            let (column, text) = statement.rest("value")?;
+            match text.parse::<T>() {
+                Ok(value) => (Instr::LoadValue(sym, value), None),
+                Err(_) => return Err(ParseError::InvalidValue { line, column, text: text.to_string() }),
+            }
+        },
+        "load_from_return" => (Instr::LoadFromReturn(statement.symbol()?), None),
+        "push_param" => (Instr::PushParam(statement.symbol()?), None),
+        "pop_param" => (Instr::PopParam(statement.symbol()?), None),
+        "load_from_exec" => {
+            let sym = statement.symbol()?;
+            let (f, name) = native!(exec);
+            (Instr::LoadFromExec(sym, f), Some(name))
+        },
+        "load_func" => {
+            let sym = statement.symbol()?;
+            (Instr::LoadFunc(sym, statement.func()?), None)
+        },
+        "load_native" => {
+            let sym = statement.symbol()?;
+            (Instr::LoadNative(sym, statement.native()?), None)
+        },
+        "call" => (Instr::Call(statement.symbol()?), None),
+        "tail_call" => (Instr::TailCall(statement.symbol()?), None),
+        "yield" => (Instr::Yield(statement.symbol()?), None),
+        "resume" => (Instr::Resume(statement.symbol()?), None),
+        "make_closure" => {
+            let sym = statement.symbol()?;
+            let func = statement.func()?;
+            let mut captures = vec![];
+            while !statement.at_end() {
+                captures.push(statement.symbol()?);
+            }
+            (Instr::MakeClosure(sym, func, captures), None)
+        },
+        "apply" => {
+            let sym = statement.symbol()?;
+            let func = statement.symbol()?;
+            let mut args = vec![];
+            while !statement.at_end() {
+                args.push(statement.symbol()?);
+            }
+            (Instr::Apply(sym, func, args), None)
+        },
+        "try" => (Instr::Try(statement.label()?), None),
+        "end_try" => (Instr::EndTry, None),
+        "throw" => (Instr::Throw(statement.symbol()?), None),
+        "catch" => (Instr::Catch(statement.symbol()?), None),
+        "sys_call" => {
+            let (f, name) = native!(sys_call);
+            (Instr::SysCall(f), Some(name))
+        },
+        "load_from_sys_call" => {
+            let sym = statement.symbol()?;
+            let (f, name) = native!(load_sys_call);
+            (Instr::LoadFromSysCall(sym, f), Some(name))
+        },
+        "load_from_poll_sys_call" => {
+            let sym = statement.symbol()?;
+            let (f, name) = native!(poll_sys_call);
+            (Instr::LoadFromPollSysCall(sym, f), Some(name))
+        },
+        "load_from_async_sys_call" => {
+            let sym = statement.symbol()?;
+            let (f, name) = native!(async_sys_call);
+            (Instr::LoadFromAsyncSysCall(sym, f), Some(name))
+        },
+        _ => return Err(ParseError::UnknownInstruction { line, column, name: name.to_string() }),
+    };
+
+    Ok(result)
+}
+
+struct Statement<'a> {
+    text : &'a str,
+    line : usize,
+    // NOTE:  Character column of the start of text within its line.
+    start_column : usize,
+    pos : usize,
+}
+
+fn statements(input : &str) -> impl Iterator<Item = Statement<'_>> {
+    input.lines().enumerate().flat_map(|(line_index, line)| {
+        let code = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+
+        let mut start = 0;
+        code.split(';').map(move |text| {
+            let statement = Statement { text
+                                      , line: line_index + 1
+                                      , start_column: code[..start].chars().count() + 1
+                                      , pos: 0
+                                      };
+            start += text.len() + 1;
+            statement
+        })
+    })
+}
+
+impl<'a> Statement<'a> {
+    fn column(&self) -> usize {
+        self.start_column + self.text[..self.pos].chars().count()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn at_end(&mut self) -> bool {
+        self.skip_whitespace();
+        self.pos == self.text.len()
+    }
+
+    fn peek_word(&mut self) -> Option<&'a str> {
+        let pos = self.pos;
+        let word = self.word("").ok().map(|(_, word)| word);
+        self.pos = pos;
+        word
+    }
+
+    fn word(&mut self, expected : &'static str) -> Result<(usize, &'a str), ParseError> {
+        self.skip_whitespace();
+        let column = self.column();
+        let rest = &self.text[self.pos..];
+
+        if rest.is_empty() {
+            return Err(ParseError::UnexpectedEnd { line: self.line, column, expected });
+        }
+
+        let len = if rest.starts_with(':') {
+            1
+        }
+        else {
+            rest.find(|c : char| c.is_whitespace() || c == ':').unwrap_or(rest.len())
+        };
+
+        self.pos += len;
+        Ok((column, &rest[..len]))
+    }
+
+    fn rest(&mut self, expected : &'static str) -> Result<(usize, &'a str), ParseError> {
+        self.skip_whitespace();
+        let column = self.column();
+        let rest = self.text[self.pos..].trim_end();
+
+        if rest.is_empty() {
+            return Err(ParseError::UnexpectedEnd { line: self.line, column, expected });
+        }
+
+        self.pos = self.text.len();
+        Ok((column, rest))
+    }
+
+    fn end(&mut self) -> Result<(), ParseError> {
+        if !self.at_end() {
+            let (column, found) = self.word("end of statement")?;
+            return Err(ParseError::UnexpectedToken { line: self.line, column, found: found.to_string(), expected: "end of statement" });
+        }
+        Ok(())
+    }
+
+    fn expect(&mut self, token : &'static str) -> Result<(), ParseError> {
+        let (column, found) = self.word(token)?;
+        if found != token {
+            return Err(ParseError::UnexpectedToken { line: self.line, column, found: found.to_string(), expected: token });
+        }
+        Ok(())
+    }
+
+    fn number(&mut self, expected : &'static str) -> Result<(usize, usize), ParseError> {
+        let (column, found) = self.word(expected)?;
+        match found.parse::<usize>() {
+            Ok(n) => Ok((column, n)),
+            Err(_) => Err(ParseError::UnexpectedToken { line: self.line, column, found: found.to_string(), expected }),
+        }
+    }
+
+    fn prefixed(&mut self, prefix : char, expected : &'static str) -> Result<usize, ParseError> {
+        let (column, found) = self.word(expected)?;
+        match found.strip_prefix(prefix).map(|n| n.parse::<usize>()) {
+            Some(Ok(n)) => Ok(n),
+            _ => Err(ParseError::UnexpectedToken { line: self.line, column, found: found.to_string(), expected }),
+        }
+    }
+
+    fn symbol(&mut self) -> Result<Symbol, ParseError> {
+        Ok(Symbol(self.prefixed('s', "symbol")?))
+    }
+
+    fn label(&mut self) -> Result<Label, ParseError> {
+        Ok(Label(self.prefixed('l', "label")?))
+    }
+
+    fn func(&mut self) -> Result<Func, ParseError> {
+        Ok(Func(self.prefixed('f', "function")?))
+    }
+
+    fn native(&mut self) -> Result<Native, ParseError> {
+        Ok(Native(self.prefixed('n', "native")?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::R;
+
+    fn registry() -> Registry<usize, usize> {
+        let mut registry = Registry::new();
+        registry.register_branch("is_zero", |locals| Ok(matches!(locals.get(&Symbol(0))?, Data::Value(0))));
+        registry.register_exec("dec", |locals| match locals.get(&Symbol(0))? {
+            Data::Value(x) => Ok(Data::Value(x - 1)),
+            _ => Ok(Data::Value(0)),
+        });
+        registry.register_sys_call("count", |_, env| { *env += 1; Ok(()) });
+        registry.register_load_sys_call("read_env", |_, env| Ok(Data::Value(*env)));
+        registry
+    }
+
+    #[test]
+    fn should_parse_and_run_program() -> R<()> {
+        let input = "
+            # counts down from the value in the environment
+            func 0:
+                load_from_sys_call s0 read_env
+                push_param s0; load_func s1 f1; call s1
+                load_from_return s0
+                return s0
+            func 1: pop_param s0
+                label l0
+                branch_on_true l1 is_zero
+                sys_call count
+                load_from_exec s0 dec
+                jump l0
+                label l1
+                return s0
+            ";
+
+        let assembly = parse(input, &registry())?;
+
+        assert_eq!( assembly.func_defs.len(), 2 );
+        assert_eq!( assembly.func_defs[&Func(1)].len(), 8 );
+        assert_eq!( assembly.native_names.get(&(Func(0), 0)).map(|n| n.as_str()), Some("read_env") );
+        assert_eq!( assembly.native_names.get(&(Func(1), 2)).map(|n| n.as_str()), Some("is_zero") );
+
+        let mut env = 3;
+        let result = crate::run(&assembly.func_defs, &mut env)?;
+
+        assert!( matches!( result, Some(Data::Value(0)) ) );
+        assert_eq!( env, 6 );
+        Ok(())
+    }
+
+    #[test]
+    fn should_parse_values_with_from_str() -> R<()> {
+        let assembly = parse::<usize, usize>("func 0: load_value s3   42  ; return s3", &registry())?;
+
+        let result = crate::run(&assembly.func_defs, &mut 0)?;
+
+        assert!( matches!( result, Some(Data::Value(42)) ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_position_of_unknown_native() {
+        let input = "func 0:\n    load_value s0 1; sys_call nope";
+
+        let error = parse::<usize, usize>(input, &registry()).err().unwrap();
+
+        assert!( matches!( error, ParseError::UnknownNative { line: 2, column: 31, .. } ) );
+    }
+
+    #[test]
+    fn should_report_bad_operand() {
+        let error = parse::<usize, usize>("func 0: call l0", &registry()).err().unwrap();
+
+        assert!( matches!( error, ParseError::UnexpectedToken { line: 1, column: 14, expected: "symbol", .. } ) );
+    }
+
+    #[test]
+    fn should_report_invalid_value() {
+        let error = parse::<usize, usize>("func 0: load_value s0 seven", &registry()).err().unwrap();
+
+        assert!( matches!( error, ParseError::InvalidValue { line: 1, column: 23, .. } ) );
+    }
+
+    #[test]
+    fn should_report_instruction_outside_function() {
+        let error = parse::<usize, usize>("\n  return s0", &registry()).err().unwrap();
+
+        assert!( matches!( error, ParseError::InstructionOutsideFunction { line: 2, column: 3 } ) );
+    }
+
+    #[test]
+    fn should_report_redefinition_of_function() {
+        let error = parse::<usize, usize>("func 0:\nfunc 0:", &registry()).err().unwrap();
+
+        assert!( matches!( error, ParseError::RedefinitionOfFunction { line: 2, column: 6, func: 0 } ) );
+    }
+
+    #[test]
+    fn should_report_missing_colon_after_function_header() {
+        let error = parse::<usize, usize>("func 0", &registry()).err().unwrap();
+        assert!( matches!( error, ParseError::UnexpectedEnd { line: 1, column: 7, expected: ":" } ) );
+
+        let error = parse::<usize, usize>("func 0 main", &registry()).err().unwrap();
+        assert!( matches!( error, ParseError::UnexpectedEnd { line: 1, column: 12, expected: ":" } ) );
+    }
+
+    #[test]
+    fn should_record_names_and_spans() -> R<()> {
+        let assembly = parse::<usize, usize>("func 0 main:\n  name s0 count; name l1 top\n  load_value s0 1; return s0", &registry())?;
+
+        assert_eq!( assembly.debug_info.func_name(&Func(0)), Some("main") );
+        assert_eq!( assembly.debug_info.symbol_name(&Func(0), &Symbol(0)), Some("count") );
+        assert_eq!( assembly.debug_info.label_name(&Func(0), &Label(1)), Some("top") );
+        assert_eq!( assembly.debug_info.span(&Func(0), 0), Some(Span { line: 3, column: 3 }) );
+        assert_eq!( assembly.debug_info.span(&Func(0), 1), Some(Span { line: 3, column: 20 }) );
+        assert_eq!( assembly.func_defs[&Func(0)].len(), 2 );
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_bad_name_target() {
+        let error = parse::<usize, usize>("func 0: name f1 main", &registry()).err().unwrap();
+
+        assert!( matches!( error, ParseError::UnexpectedToken { line: 1, column: 14, expected: "symbol or label", .. } ) );
+    }
+}
@@ -0,0 +1,256 @@
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::error::VmError;
+use crate::data::*;
+use crate::debug::DebugInfo;
+use crate::R;
+
+/// A program whose labels have been resolved into instruction offsets.  Compile once and hand the
+/// result to as many vms as needed.
+pub struct CompiledProgram<'a, T : Clone, Env> {
+    funcs : HashMap<Func, CompiledFunc<'a, T, Env>>,
+    natives : HashMap<Native, NativeFn<T, Env>>,
+    debug_info : Option<DebugInfo>,
+}
+
+pub(crate) struct CompiledFunc<'a, T : Clone, Env> {
+    pub ops : Vec<Op<'a, T, Env>>,
+    /// Index of the source instruction each op was compiled from.
+    pub source_index : Vec<usize>,
+    pub source : &'a [Instr<T, Env>],
+    pub slot_map : Rc<SlotMap>,
+}
+
+/// Where a label resolved to.  A missing label is only an error when control reaches it, so
+/// unreachable code may name one.  `verify` reports them ahead of time.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Target {
+    Offset(usize),
+    Missing(Label),
+}
+
+/// Instr with jump targets replaced by op offsets, symbols replaced by slots and labels removed.
+pub(crate) enum Op<'a, T : Clone, Env> {
+    Jump(Target),
+    BranchOnTrue(Target, &'a BranchFn<T>),
+    Return(Slot),
+    LoadValue(Slot, &'a T),
+    LoadFromReturn(Slot),
+    PushParam(Slot),
+    PopParam(Slot),
+    LoadFromExec(Slot, &'a ExecFn<T>),
+    LoadFunc(Slot, Func),
+    LoadNative(Slot, Native),
+    Call(Slot),
+    TailCall(Slot),
+    Yield(Slot),
+    Resume(Slot),
+    MakeClosure(Slot, Func, Vec<(Symbol, Slot)>),
+    Apply(Slot, Slot, Vec<Slot>),
+    Try(Target),
+    EndTry,
+    Throw(Slot),
+    Catch(Slot),
+    SysCall(&'a SysCallFn<T, Env>),
+    LoadFromSysCall(Slot, &'a LoadSysCallFn<T, Env>),
+    LoadFromPollSysCall(Slot, &'a PollSysCallFn<T, Env>),
+    LoadFromAsyncSysCall(Slot, &'a AsyncSysCallFn<T, Env>),
+}
+
+impl<'a, T : Clone, Env> CompiledProgram<'a, T, Env> {
+    pub fn contains(&self, func : &Func) -> bool {
+        self.funcs.contains_key(func)
+    }
+
+    pub fn funcs(&self) -> impl Iterator<Item = Func> + '_ {
+        self.funcs.keys().copied()
+    }
+
+    /// The source instructions a function was compiled from.
+    pub fn source(&self, func : &Func) -> Option<&'a [Instr<T, Env>]> {
+        self.funcs.get(func).map(|f| f.source)
+    }
+
+    /// Makes a host function callable from the program through Data::Native.
+    pub fn register_native(&mut self, native : Native, f : impl Fn(&mut Params<T>, &mut Env) -> R<Data<T>> + 'static) {
+        self.natives.insert(native, Box::new(f));
+    }
+
+    /// Attaches names and source spans which are then used by error messages and stack traces.
+    pub fn set_debug_info(&mut self, debug_info : DebugInfo) {
+        self.debug_info = Some(debug_info);
+    }
+
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
+    pub(crate) fn func(&self, func : &Func) -> Option<&CompiledFunc<'a, T, Env>> {
+        self.funcs.get(func)
+    }
+
+    pub(crate) fn native(&self, native : &Native) -> Option<&NativeFn<T, Env>> {
+        self.natives.get(native)
+    }
+}
+
+impl<'a, T : Clone, Env> CompiledFunc<'a, T, Env> {
+    /// Maps an op offset back to the index of the source instruction.  The offset just past the
+    /// last op maps to the end of the source.
+    pub fn source_index(&self, op : usize) -> usize {
+        match self.source_index.get(op) {
+            Some(index) => *index,
+            None => self.source.len(),
+        }
+    }
+
+    pub fn symbol(&self, slot : Slot) -> Symbol {
+        self.slot_map.symbol(slot)
+    }
+}
+
+pub fn compile<T : Clone, Env>( func_defs : &HashMap<Func, Vec<Instr<T, Env>>> ) -> R<CompiledProgram<'_, T, Env>> {
+    let funcs = func_defs.iter()
+                         .map(|(func, instrs)| Ok((*func, compile_func(instrs, *func)?)))
+                         .collect::<R<HashMap<_, _>>>()?;
+
+    Ok(CompiledProgram { funcs, natives: HashMap::new(), debug_info: None })
+}
+
+fn compile_func<T : Clone, Env>( instrs : &[Instr<T, Env>], current_function : Func ) -> R<CompiledFunc<'_, T, Env>> {
+
+    // NOTE:  A label resolves to the offset of the first op that follows it.
+    let mut label_map : HashMap<Label, usize> = HashMap::new();
+    let mut offset = 0;
+    for instr in instrs {
+        match instr {
+            Instr::Label(label) => {
+                if label_map.insert(*label, offset).is_some() {
+                    return Err(Box::new(VmError::RedefinitionOfLabel { label : label.0, func : current_function.0 }));
+                }
+            },
+            _ => { offset += 1; },
+        }
+    }
+
+    let target = |label : &Label| -> Target {
+        match label_map.get(label) {
+            Some(offset) => Target::Offset(*offset),
+            None => Target::Missing(*label),
+        }
+    };
+
+    let mut slot_map = SlotMap::default();
+    let mut ops = vec![];
+    let mut source_index = vec![];
+    for (index, instr) in instrs.iter().enumerate() {
+        let mut slot = |sym : &Symbol| slot_map.slot(sym);
+        let op = match instr {
+            Instr::Label(_) => continue,
+            Instr::Jump(label) => Op::Jump(target(label)),
+            Instr::BranchOnTrue(label, f) => Op::BranchOnTrue(target(label), f),
+            Instr::Return(sym) => Op::Return(slot(sym)),
+            Instr::LoadValue(sym, data) => Op::LoadValue(slot(sym), data),
+            Instr::LoadFromReturn(sym) => Op::LoadFromReturn(slot(sym)),
+            Instr::PushParam(sym) => Op::PushParam(slot(sym)),
+            Instr::PopParam(sym) => Op::PopParam(slot(sym)),
+            Instr::LoadFromExec(sym, f) => Op::LoadFromExec(slot(sym), f),
+            Instr::LoadFunc(sym, f) => Op::LoadFunc(slot(sym), *f),
+            Instr::LoadNative(sym, n) => Op::LoadNative(slot(sym), *n),
+            Instr::Call(sym) => Op::Call(slot(sym)),
+            Instr::TailCall(sym) => Op::TailCall(slot(sym)),
+            Instr::Yield(sym) => Op::Yield(slot(sym)),
+            Instr::Resume(sym) => Op::Resume(slot(sym)),
+            Instr::MakeClosure(sym, f, captures) =>
+                Op::MakeClosure(slot(sym), *f, captures.iter().map(|c| (*c, slot(c))).collect()),
+            Instr::Apply(sym, f, args) => Op::Apply(slot(sym), slot(f), args.iter().map(&mut slot).collect()),
+            Instr::Try(label) => Op::Try(target(label)),
+            Instr::EndTry => Op::EndTry,
+            Instr::Throw(sym) => Op::Throw(slot(sym)),
+            Instr::Catch(sym) => Op::Catch(slot(sym)),
+            Instr::SysCall(f) => Op::SysCall(f),
+            Instr::LoadFromSysCall(sym, f) => Op::LoadFromSysCall(slot(sym), f),
+            Instr::LoadFromPollSysCall(sym, f) => Op::LoadFromPollSysCall(slot(sym), f),
+            Instr::LoadFromAsyncSysCall(sym, f) => Op::LoadFromAsyncSysCall(slot(sym), f),
+        };
+        ops.push(op);
+        source_index.push(index);
+    }
+
+    Ok(CompiledFunc { ops, source_index, source: instrs, slot_map: Rc::new(slot_map) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_strip_labels_and_resolve_targets() -> R<()> {
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::Label(Label(0))
+                           , Instr::LoadValue(Symbol(0), 1)
+                           , Instr::Label(Label(1))
+                           , Instr::Jump(Label(1))
+                           , Instr::Jump(Label(0))
+                           , Instr::Label(Label(2))
+                           , Instr::Jump(Label(2))
+                           ])
+            ]);
+
+        let program = compile(&func_defs)?;
+        let func = program.func(&Func(0)).unwrap();
+
+        assert_eq!( func.ops.len(), 4 );
+        assert_eq!( func.source_index, vec![1, 3, 4, 6] );
+        assert!( matches!( func.ops[1], Op::Jump(Target::Offset(1)) ) );
+        assert!( matches!( func.ops[2], Op::Jump(Target::Offset(0)) ) );
+        assert!( matches!( func.ops[3], Op::Jump(Target::Offset(3)) ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_assign_dense_slots_per_function() -> R<()> {
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadValue(Symbol(40), 1)
+                           , Instr::LoadValue(Symbol(7), 2)
+                           , Instr::PushParam(Symbol(40))
+                           , Instr::Return(Symbol(7))
+                           ])
+            ]);
+
+        let program = compile(&func_defs)?;
+        let func = program.func(&Func(0)).unwrap();
+
+        assert_eq!( func.slot_map.symbols, vec![Symbol(40), Symbol(7)] );
+        assert!( matches!( func.ops[2], Op::PushParam(Slot(0)) ) );
+        assert!( matches!( func.ops[3], Op::Return(Slot(1)) ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_fail_on_missing_label_only_when_reached() -> R<()> {
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadValue(Symbol(0), 1)
+                           , Instr::Return(Symbol(0))
+                           , Instr::Jump(Label(4))
+                           ])
+            ,(Func(1), vec![ Instr::Jump(Label(4)) ])
+            ]);
+
+        let program = compile(&func_defs)?;
+        let func = program.func(&Func(1)).unwrap();
+        assert!( matches!( func.ops[0], Op::Jump(Target::Missing(Label(4))) ) );
+
+        assert!( matches!( crate::run_compiled(&program, &mut ())?, Some(Data::Value(1)) ) );
+
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::Jump(Label(4)) ])
+            ]);
+        let error = crate::run(&func_defs, &mut ()).unwrap_err();
+        let error = &error.downcast_ref::<crate::error::RuntimeError>().unwrap().error;
+        assert!( matches!( error.downcast_ref::<VmError>(), Some(VmError::LabelDoesNotExist { func: 0, label: 4 }) ) );
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+
+//! A tracer which collects instruction and branch coverage.  The same collector can be passed to
+//! any number of runs, and the report covers all of them.  Labels are never executed, so they are
+//! not counted.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::data::*;
+use crate::trace::Tracer;
+
+#[derive(Debug, Default)]
+pub struct Coverage {
+    executed : HashSet<(Func, usize)>,
+    /// Whether each branch was seen to jump and to fall through.
+    branches : HashMap<(Func, usize), (bool, bool)>,
+    entered : HashSet<Func>,
+}
+
+#[derive(Debug, Default)]
+pub struct CoverageReport {
+    pub covered : usize,
+    pub total : usize,
+    /// Instructions never executed, ordered by function and then by instruction.
+    pub uncovered : Vec<(Func, usize)>,
+    /// Branch outcomes never seen, where true means the branch jumped.
+    pub missed_branches : Vec<(Func, usize, bool)>,
+    pub never_entered : Vec<Func>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage::default()
+    }
+
+    pub fn is_executed(&self, func : Func, index : usize) -> bool {
+        self.executed.contains(&(func, index))
+    }
+
+    /// Whether the branch at the index was seen to jump and to fall through.
+    pub fn branch(&self, func : Func, index : usize) -> Option<(bool, bool)> {
+        self.branches.get(&(func, index)).copied()
+    }
+
+    pub fn is_entered(&self, func : Func) -> bool {
+        self.entered.contains(&func)
+    }
+
+    pub fn report<T : Clone, Env>( &self, func_defs : &HashMap<Func, Vec<Instr<T, Env>>> ) -> CoverageReport {
+        let mut funcs = func_defs.keys().copied().collect::<Vec<_>>();
+        funcs.sort_by_key(|func| func.0);
+
+        let mut report = CoverageReport::default();
+        for func in funcs {
+            if !self.is_entered(func) {
+                report.never_entered.push(func);
+            }
+
+            for (index, instr) in func_defs[&func].iter().enumerate() {
+                match instr {
+                    Instr::Label(_) => continue,
+                    Instr::BranchOnTrue(_, _) => {
+                        let (taken, not_taken) = self.branch(func, index).unwrap_or((false, false));
+                        if !taken {
+                            report.missed_branches.push((func, index, true));
+                        }
+                        if !not_taken {
+                            report.missed_branches.push((func, index, false));
+                        }
+                    },
+                    _ => { },
+                }
+
+                report.total += 1;
+                if self.is_executed(func, index) {
+                    report.covered += 1;
+                }
+                else {
+                    report.uncovered.push((func, index));
+                }
+            }
+        }
+        report
+    }
+}
+
+impl<T : Clone, Env> Tracer<T, Env> for Coverage {
+    fn on_instr(&mut self, func : Func, ip : usize, _instr : &Instr<T, Env>) {
+        self.entered.insert(func);
+        self.executed.insert((func, ip));
+    }
+
+    fn on_branch(&mut self, func : Func, ip : usize, taken : bool) {
+        let outcomes = self.branches.entry((func, ip)).or_default();
+        if taken {
+            outcomes.0 = true;
+        }
+        else {
+            outcomes.1 = true;
+        }
+    }
+
+    fn on_call(&mut self, _from : Func, to : Func, _depth : usize) {
+        self.entered.insert(to);
+    }
+}
+
+impl std::fmt::Display for CoverageReport {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} of {} instructions covered", self.covered, self.total)?;
+        for func in &self.never_entered {
+            write!(f, "\n  function {} never entered", func.0)?;
+        }
+        for (func, index) in &self.uncovered {
+            write!(f, "\n  function {} instruction {} not executed", func.0, index)?;
+        }
+        for (func, index, taken) in &self.missed_branches {
+            let outcome = if *taken { "taken" } else { "not taken" };
+            write!(f, "\n  function {} instruction {} branch never {}", func.0, index, outcome)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::R;
+
+    fn program() -> HashMap<Func, Vec<Instr<u8, u8>>> {
+        HashMap::from(
+            [(Func(0), vec![ Instr::LoadFromSysCall(Symbol(0), Box::new(|_, env| Ok(Data::Value(*env))))
+                           , Instr::BranchOnTrue(Label(0), Box::new(|locals| Ok(matches!(locals.get(&Symbol(0))?, Data::Value(0)))))
+                           , Instr::LoadValue(Symbol(0), 1)
+                           , Instr::Label(Label(0))
+                           , Instr::Return(Symbol(0))
+                           ])
+            ,(Func(1), vec![ Instr::Return(Symbol(0)) ])
+            ])
+    }
+
+    #[test]
+    fn should_report_uncovered_instructions_and_branches() -> R<()> {
+        let func_defs = program();
+        let mut coverage = Coverage::new();
+        crate::run_traced(&func_defs, &mut 0, &mut coverage)?;
+
+        let report = coverage.report(&func_defs);
+
+        assert_eq!( (report.covered, report.total), (3, 5) );
+        assert_eq!( report.uncovered, vec![(Func(0), 2), (Func(1), 0)] );
+        assert_eq!( report.missed_branches, vec![(Func(0), 1, false)] );
+        assert_eq!( report.never_entered, vec![Func(1)] );
+        assert_eq!( report.to_string()
+                  , "3 of 5 instructions covered\n  function 1 never entered\n  function 0 instruction 2 not executed\n  function 1 instruction 0 not executed\n  function 0 instruction 1 branch never not taken"
+                  );
+        Ok(())
+    }
+
+    #[test]
+    fn should_accumulate_coverage_across_runs() -> R<()> {
+        let func_defs = program();
+        let mut coverage = Coverage::new();
+        crate::run_traced(&func_defs, &mut 0, &mut coverage)?;
+        crate::run_traced(&func_defs, &mut 1, &mut coverage)?;
+
+        let report = coverage.report(&func_defs);
+
+        assert_eq!( coverage.branch(Func(0), 1), Some((true, true)) );
+        assert_eq!( report.uncovered, vec![(Func(1), 0)] );
+        assert!( report.missed_branches.is_empty() );
+        Ok(())
+    }
+}
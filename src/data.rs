@@ -1,11 +1,46 @@
 
 use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::Poll;
 use crate::error::VmError;
+use crate::vm::Frame;
+use crate::R;
 
 #[derive(Debug, Clone)]
 pub enum Data<T : Clone> {
     Value(T),
     Func(Func),
+    /// A function along with the values of the symbols it captured.  Calling it sets each
+    /// captured symbol in the callee's locals.
+    Closure(Func, Rc<Vec<(Symbol, Data<T>)>>),
+    /// A function, closure or native along with arguments that have already been supplied.
+    /// Calling it pushes the arguments onto the params so that the callee pops them first, in
+    /// order.
+    Partial(Rc<Data<T>>, Rc<Vec<Data<T>>>),
+    /// A host function registered with the compiled program.
+    Native(Native),
+    /// A function suspended by `Yield`.
+    Coroutine(Coroutine<T>),
+}
+
+/// The saved frame of a suspended function.  Resuming it continues after the yield that
+/// suspended it.  Once the function returns the coroutine is finished and cannot be resumed.
+#[derive(Debug, Clone)]
+pub struct Coroutine<T : Clone> {
+    pub(crate) frame : Option<Rc<Frame<T>>>,
+}
+
+impl<T : Clone> Coroutine<T> {
+    pub fn is_finished(&self) -> bool {
+        self.frame.is_none()
+    }
+
+    /// The function that will continue when resumed.
+    pub fn func(&self) -> Option<Func> {
+        self.frame.as_ref().map(|frame| frame.current_function)
+    }
 }
 
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
@@ -14,43 +49,348 @@ pub struct Func(pub usize);
 pub struct Label(pub usize);
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
 pub struct Symbol(pub usize);
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct Native(pub usize);
+
+impl std::fmt::Display for Func {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "f{}", self.0)
+    }
+}
+
+impl std::fmt::Display for Label {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "l{}", self.0)
+    }
+}
+
+impl std::fmt::Display for Symbol {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "s{}", self.0)
+    }
+}
+
+impl std::fmt::Display for Native {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "n{}", self.0)
+    }
+}
+
+pub type BranchFn<T> = Box<dyn Fn(&Locals<T>) -> R<bool>>;
+pub type ExecFn<T> = Box<dyn Fn(&Locals<T>) -> R<Data<T>>>;
+pub type SysCallFn<T, Env> = Box<dyn Fn(&mut Locals<T>, &mut Env) -> R<()>>;
+pub type LoadSysCallFn<T, Env> = Box<dyn Fn(&mut Locals<T>, &mut Env) -> R<Data<T>>>;
+pub type PollSysCallFn<T, Env> = Box<dyn Fn(&mut Locals<T>, &mut Env) -> R<Poll<Data<T>>>>;
+pub type SysCallFuture<T> = Pin<Box<dyn Future<Output = R<Data<T>>>>>;
+pub type AsyncSysCallFn<T, Env> = Box<dyn Fn(&mut Locals<T>, &mut Env) -> SysCallFuture<T>>;
+/// Pops its own arguments off of the params.  The result becomes the return value.
+pub type NativeFn<T, Env> = Box<dyn Fn(&mut Params<T>, &mut Env) -> R<Data<T>>>;
 
 pub enum Instr<T : Clone, Env> { 
     Label(Label),
     Jump(Label),
-    BranchOnTrue(Label, Box<dyn Fn(&Locals<T>) -> Result<bool, Box<dyn std::error::Error>>>),
+    BranchOnTrue(Label, BranchFn<T>),
     Return(Symbol),
     LoadValue(Symbol, T),
     LoadFromReturn(Symbol),
     PushParam(Symbol),
     PopParam(Symbol),
-    LoadFromExec(Symbol, Box<dyn Fn(&Locals<T>) -> Result<Data<T>, Box<dyn std::error::Error>>>),
+    LoadFromExec(Symbol, ExecFn<T>),
     LoadFunc(Symbol, Func),
+    LoadNative(Symbol, Native),
     Call(Symbol), 
-    SysCall(Box<dyn Fn(&mut Locals<T>, &mut Env) -> Result<(), Box<dyn std::error::Error>>>),
-    LoadFromSysCall(Symbol, Box<dyn Fn(&mut Locals<T>, &mut Env) -> Result<Data<T>, Box<dyn std::error::Error>>>),
+    /// Calls the symbol in place of the current function, which returns straight to its caller.
+    TailCall(Symbol),
+    /// Suspends the current function, which must have been started by `Resume`.  The resumer's
+    /// symbol is set to the suspended coroutine and the value is its return.
+    Yield(Symbol),
+    /// Starts the function in the symbol as a coroutine, or continues the coroutine in it.
+    Resume(Symbol),
+    MakeClosure(Symbol, Func, Vec<Symbol>),
+    Apply(Symbol, Symbol, Vec<Symbol>),
+    /// Starts a try region whose handler begins at the label.
+    Try(Label),
+    EndTry,
+    Throw(Symbol),
+    /// Sets the symbol to the exception being handled.
+    Catch(Symbol),
+    SysCall(SysCallFn<T, Env>),
+    LoadFromSysCall(Symbol, LoadSysCallFn<T, Env>),
+    /// Like LoadFromSysCall, but the sys call may answer pending.  The vm then suspends until the
+    /// host completes it with the value for the symbol.
+    LoadFromPollSysCall(Symbol, PollSysCallFn<T, Env>),
+    /// Suspends the vm until the future returned by the sys call resolves to the value for the
+    /// symbol.
+    LoadFromAsyncSysCall(Symbol, AsyncSysCallFn<T, Env>),
+}
+
+/// Dense slot assigned to each symbol a compiled function mentions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Slot(pub usize);
+
+#[derive(Debug, Default)]
+pub(crate) struct SlotMap {
+    pub symbols : Vec<Symbol>,
+    pub slots : HashMap<Symbol, Slot>,
+}
+
+impl SlotMap {
+    pub fn slot(&mut self, sym : &Symbol) -> Slot {
+        match self.slots.get(sym) {
+            Some(slot) => *slot,
+            None => {
+                let slot = Slot(self.symbols.len());
+                self.symbols.push(*sym);
+                self.slots.insert(*sym, slot);
+                slot
+            },
+        }
+    }
+
+    pub fn symbol(&self, slot : Slot) -> Symbol {
+        self.symbols[slot.0]
+    }
+}
+
+/// The param stack as a native function sees it.  Params can only be popped off of the top and
+/// pushed onto it.
+pub struct Params<'b, T : Clone> {
+    stack : &'b mut Vec<Data<T>>,
+    // NOTE:  The fewest params the stack held, so that a recording vm can log what the native
+    // popped and pushed without copying the params it left alone.
+    low : usize,
+}
+
+impl<'b, T : Clone> Params<'b, T> {
+    pub(crate) fn new(stack : &'b mut Vec<Data<T>>) -> Self {
+        let low = stack.len();
+        Params { stack, low }
+    }
+
+    pub fn pop(&mut self) -> Option<Data<T>> {
+        let data = self.stack.pop();
+        self.low = self.low.min(self.stack.len());
+        data
+    }
+
+    pub fn push(&mut self, data : Data<T>) {
+        self.stack.push(data);
+    }
+
+    pub fn last(&self) -> Option<&Data<T>> {
+        self.stack.last()
+    }
+
+    pub fn len(&self) -> usize {
+        self.stack.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stack.is_empty()
+    }
+
+    pub(crate) fn low(&self) -> usize {
+        self.low
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Locals<T> where T : Clone {
     f : usize,
-    v : HashMap<Symbol, Data<T>>,
+    slot_map : Rc<SlotMap>,
+    v : Vec<Option<Data<T>>>,
+    // NOTE:  Symbols which the compiled function never mentions can still be set by closures, so
+    // they are kept here instead of in a slot.
+    extra : HashMap<Symbol, Data<T>>,
+    count : usize,
+    max : Option<usize>,
+    // NOTE:  Symbols written while a vm records a sys call or a debugger watches are noted here,
+    // whether the vm or a host closure wrote them.
+    journal : Option<Vec<Symbol>>,
 } 
 
 impl<T> Locals<T> where T : Clone {
     pub fn new(func : usize) -> Self {
-        Locals { v : HashMap::new(), f : func }
+        Locals::with_limit(func, None)
+    }
+
+    /// Locals which refuse to hold more than max distinct symbols.
+    pub fn with_limit(func : usize, max : Option<usize>) -> Self {
+        Locals::with_slots(func, Rc::new(SlotMap::default()), max)
+    }
+
+    pub(crate) fn with_slots(func : usize, slot_map : Rc<SlotMap>, max : Option<usize>) -> Self {
+        let v = (0..slot_map.symbols.len()).map(|_| None).collect();
+        Locals { f : func, slot_map, v, extra : HashMap::new(), count : 0, max, journal : None }
     }
 
     pub fn get(&self, sym : &Symbol) -> Result<Data<T>, Box<dyn std::error::Error>> {
-        match self.v.get(sym) {
-            Some(x) => Ok(x.clone()),
-            None => Err(Box::new(VmError::SymbolDoesNotExist { func : self.f, sym : sym.0 })),
+        self.get_ref(sym).cloned()
+    }
+
+    pub fn get_ref(&self, sym : &Symbol) -> Result<&Data<T>, Box<dyn std::error::Error>> {
+        let data = match self.slot_map.slots.get(sym) {
+            Some(slot) => self.v[slot.0].as_ref(),
+            None => self.extra.get(sym),
+        };
+        data.ok_or_else(|| self.missing(sym))
+    }
+
+    pub fn get_mut(&mut self, sym : &Symbol) -> Result<&mut Data<T>, Box<dyn std::error::Error>> {
+        self.note(sym);
+        let missing = self.missing(sym);
+        let data = match self.slot_map.slots.get(sym) {
+            Some(slot) => self.v[slot.0].as_mut(),
+            None => self.extra.get_mut(sym),
+        };
+        data.ok_or(missing)
+    }
+
+    /// Moves the data out of the symbol, leaving the symbol unset.
+    pub fn take(&mut self, sym : &Symbol) -> Result<Data<T>, Box<dyn std::error::Error>> {
+        let data = match self.slot_map.slots.get(sym) {
+            Some(slot) => self.v[slot.0].take(),
+            None => self.extra.remove(sym),
+        };
+        match data {
+            Some(data) => {
+                self.note(sym);
+                self.count -= 1;
+                Ok(data)
+            },
+            None => Err(self.missing(sym)),
+        }
+    }
+
+    /// Borrows the value held by the symbol, failing if the symbol holds something else.
+    pub fn value_ref(&self, sym : &Symbol) -> Result<&T, Box<dyn std::error::Error>> {
+        match self.get_ref(sym)? {
+            Data::Value(value) => Ok(value),
+            _ => Err(Box::new(VmError::SymbolIsNotValue { func : self.f, sym : sym.0 })),
         }
     }
 
     pub fn set(&mut self, sym : &Symbol, data : Data<T>) -> Result<(), Box<dyn std::error::Error>> {
-        self.v.insert(*sym, data);
+        match self.slot_map.slots.get(sym) {
+            Some(slot) => self.set_slot(*slot, data),
+            None => {
+                self.note(sym);
+                if !self.extra.contains_key(sym) {
+                    self.grow()?;
+                }
+                self.extra.insert(*sym, data);
+                Ok(())
+            },
+        }
+    }
+
+    pub(crate) fn get_slot(&self, slot : Slot) -> Result<Data<T>, Box<dyn std::error::Error>> {
+        match &self.v[slot.0] {
+            Some(x) => Ok(x.clone()),
+            None => Err(Box::new(VmError::SymbolDoesNotExist { func : self.f, sym : self.slot_map.symbol(slot).0 })),
+        }
+    }
+
+    pub(crate) fn set_slot(&mut self, slot : Slot, data : Data<T>) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(journal) = &mut self.journal {
+            journal.push(self.slot_map.symbol(slot));
+        }
+        if self.v[slot.0].is_none() {
+            self.grow()?;
+        }
+        self.v[slot.0] = Some(data);
+        Ok(())
+    }
+
+    pub(crate) fn is_journaling(&self) -> bool {
+        self.journal.is_some()
+    }
+
+    /// Starts noting writes unless they are already noted, and returns how many were noted so far.
+    pub(crate) fn start_journal(&mut self) -> usize {
+        self.journal.get_or_insert_with(Vec::new).len()
+    }
+
+    /// Each symbol written since the journal held `from` writes, once.
+    pub(crate) fn written_since(&self, from : usize) -> Vec<Symbol> {
+        let mut written = self.journal.as_ref().map(|journal| journal[from..].to_vec()).unwrap_or_default();
+        written.sort_by_key(|sym| sym.0);
+        written.dedup_by_key(|sym| sym.0);
+        written
+    }
+
+    /// Stops noting writes and returns each symbol written since the journal started, once.
+    pub(crate) fn take_journal(&mut self) -> Vec<Symbol> {
+        let written = self.written_since(0);
+        self.journal = None;
+        written
+    }
+
+    fn note(&mut self, sym : &Symbol) {
+        if let Some(journal) = &mut self.journal {
+            journal.push(*sym);
+        }
+    }
+
+    fn missing(&self, sym : &Symbol) -> Box<dyn std::error::Error> {
+        Box::new(VmError::SymbolDoesNotExist { func : self.f, sym : sym.0 })
+    }
+
+    fn grow(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(max) = self.max {
+            if self.count >= max {
+                return Err(Box::new(VmError::LocalsOverflow { func : self.f, count : self.count + 1 }));
+            }
+        }
+        self.count += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_borrow_and_mutate_without_cloning() -> R<()> {
+        let sym = Symbol(0);
+        let mut locals : Locals<Vec<u8>> = Locals::new(0);
+        locals.set(&sym, Data::Value(vec![1, 2]))?;
+
+        assert_eq!( locals.value_ref(&sym)?, &vec![1, 2] );
+
+        if let Data::Value(v) = locals.get_mut(&sym)? {
+            v.push(3);
+        }
+
+        assert!( matches!( locals.get_ref(&sym)?, Data::Value(v) if v.len() == 3 ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_take_value_and_unset_symbol() -> R<()> {
+        let sym = Symbol(0);
+        let mut locals : Locals<u8> = Locals::with_limit(0, Some(1));
+        locals.set(&sym, Data::Value(4))?;
+
+        assert!( matches!( locals.take(&sym)?, Data::Value(4) ) );
+        assert!( locals.get_ref(&sym).is_err() );
+        assert!( locals.take(&sym).is_err() );
+
+        // NOTE:  Taking frees the symbol's place under the locals limit.
+        locals.set(&Symbol(1), Data::Value(5))?;
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_value_ref_of_function() -> R<()> {
+        let sym = Symbol(2);
+        let mut locals : Locals<u8> = Locals::new(3);
+        locals.set(&sym, Data::Func(Func(1)))?;
+
+        let error = locals.value_ref(&sym).unwrap_err();
+
+        assert!( matches!( error.downcast_ref::<VmError>(), Some(VmError::SymbolIsNotValue { func: 3, sym: 2 }) ) );
         Ok(())
     }
 }
@@ -0,0 +1,72 @@
+
+use std::collections::HashMap;
+
+use crate::data::*;
+
+/// Where an instruction came from in its source text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub line : usize,
+    pub column : usize,
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// Human names for the ids in a program and source spans for its instructions.  Symbols and
+/// labels are scoped to the function they appear in.
+#[derive(Debug, Clone, Default)]
+pub struct DebugInfo {
+    pub func_names : HashMap<Func, String>,
+    pub symbol_names : HashMap<(Func, Symbol), String>,
+    pub label_names : HashMap<(Func, Label), String>,
+    /// Span of the instruction at (function, index).
+    pub spans : HashMap<(Func, usize), Span>,
+}
+
+impl DebugInfo {
+    pub fn new() -> Self {
+        DebugInfo::default()
+    }
+
+    pub fn func_name(&self, func : &Func) -> Option<&str> {
+        self.func_names.get(func).map(|name| name.as_str())
+    }
+
+    pub fn symbol_name(&self, func : &Func, sym : &Symbol) -> Option<&str> {
+        self.symbol_names.get(&(*func, *sym)).map(|name| name.as_str())
+    }
+
+    pub fn label_name(&self, func : &Func, label : &Label) -> Option<&str> {
+        self.label_names.get(&(*func, *label)).map(|name| name.as_str())
+    }
+
+    pub fn span(&self, func : &Func, index : usize) -> Option<Span> {
+        self.spans.get(&(*func, index)).copied()
+    }
+
+    /// The function's name, or its number when it has none.
+    pub fn describe_func(&self, func : &Func) -> String {
+        match self.func_name(func) {
+            Some(name) => name.to_string(),
+            None => func.0.to_string(),
+        }
+    }
+
+    pub fn describe_symbol(&self, func : &Func, sym : &Symbol) -> String {
+        match self.symbol_name(func, sym) {
+            Some(name) => name.to_string(),
+            None => sym.0.to_string(),
+        }
+    }
+
+    pub fn describe_label(&self, func : &Func, label : &Label) -> String {
+        match self.label_name(func, label) {
+            Some(name) => name.to_string(),
+            None => label.0.to_string(),
+        }
+    }
+}
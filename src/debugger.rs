@@ -0,0 +1,314 @@
+
+//! Interactive control over a vm: breakpoints, stepping and watchpoints.
+//!
+//! Breakpoints are source instruction indices, as reported by `Vm::instr_ptr`.  A breakpoint on a
+//! label stops at the first instruction after it, since labels are not executed.  Watchpoints
+//! stop after any instruction which writes the symbol in a frame of the function, even with the
+//! data it already held, including writes made by host closures.  Writes are only noted while
+//! there are watchpoints.
+
+use std::collections::HashSet;
+
+use crate::data::*;
+use crate::error::VmError;
+use crate::trace::{Tracer, NoTracer};
+use crate::vm::{Vm, FrameView, Status};
+use crate::R;
+
+/// Why the debugger handed control back.
+#[derive(Debug, Clone)]
+pub enum Pause<T : Clone> {
+    /// The requested step completed.
+    Step,
+    /// The next instruction to execute has a breakpoint.
+    Breakpoint { func : Func, index : usize },
+    /// The last instruction wrote a watched symbol.
+    Watchpoint { func : Func, sym : Symbol },
+    Finished(Option<Data<T>>),
+    Pending,
+    OutOfFuel,
+}
+
+pub struct Debugger<'a, T : Clone, Env, Tr = NoTracer> {
+    vm : Vm<'a, T, Env, Tr>,
+    breakpoints : HashSet<(Func, usize)>,
+    watchpoints : HashSet<(Func, Symbol)>,
+    started : bool,
+}
+
+impl<'a, T : Clone, Env, Tr : Tracer<T, Env>> Debugger<'a, T, Env, Tr> {
+    pub fn new( vm : Vm<'a, T, Env, Tr> ) -> Self {
+        Debugger { vm, breakpoints: HashSet::new(), watchpoints: HashSet::new(), started: false }
+    }
+
+    pub fn vm(&self) -> &Vm<'a, T, Env, Tr> { &self.vm }
+    pub fn vm_mut(&mut self) -> &mut Vm<'a, T, Env, Tr> { &mut self.vm }
+    pub fn into_vm(self) -> Vm<'a, T, Env, Tr> { self.vm }
+
+    /// The active frames and their locals, innermost first.
+    pub fn frames(&self) -> Vec<FrameView<'_, T>> {
+        self.vm.frames()
+    }
+
+    /// Sets a breakpoint and returns the index of the instruction it stops at.  Fails when no
+    /// instruction is executed at or after the index.
+    pub fn set_breakpoint(&mut self, func : Func, index : usize) -> R<usize> {
+        let source = self.source(func)?;
+        let executed = first_executed(source, index);
+        if executed >= source.len() {
+            return Err(Box::new(VmError::InstructionDoesNotExist { func: func.0, index }));
+        }
+        self.breakpoints.insert((func, executed));
+        Ok(executed)
+    }
+
+    pub fn set_label_breakpoint(&mut self, func : Func, label : Label) -> R<usize> {
+        let source = self.source(func)?;
+        match source.iter().position(|instr| matches!(instr, Instr::Label(l) if *l == label)) {
+            Some(index) => self.set_breakpoint(func, index),
+            None => Err(Box::new(VmError::LabelDoesNotExist { func: func.0, label: label.0 })),
+        }
+    }
+
+    pub fn clear_breakpoint(&mut self, func : Func, index : usize) -> bool {
+        self.breakpoints.remove(&(func, index))
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = &(Func, usize)> {
+        self.breakpoints.iter()
+    }
+
+    pub fn watch(&mut self, func : Func, sym : Symbol) {
+        self.watchpoints.insert((func, sym));
+    }
+
+    pub fn unwatch(&mut self, func : Func, sym : Symbol) -> bool {
+        self.watchpoints.remove(&(func, sym))
+    }
+
+    /// Runs until a breakpoint, a watchpoint or the end of the program.
+    pub fn run(&mut self, env : &mut Env) -> R<Pause<T>> {
+        // NOTE:  Otherwise the current instruction is executed before breakpoints are checked, so
+        // that continuing from a breakpoint does not stop at it again.
+        if !self.started {
+            self.started = true;
+            if let Some(pause) = self.breakpoint() {
+                return Ok(pause);
+            }
+        }
+        self.run_until(env, |_| false)
+    }
+
+    /// Executes one instruction, following calls into the callee.
+    pub fn step_into(&mut self, env : &mut Env) -> R<Pause<T>> {
+        self.run_until(env, |_| true)
+    }
+
+    /// Executes one instruction, running any function it calls to completion.
+    pub fn step_over(&mut self, env : &mut Env) -> R<Pause<T>> {
+        let depth = self.vm.depth();
+        self.run_until(env, |d| d <= depth)
+    }
+
+    /// Runs until the current function returns to its caller.
+    pub fn step_out(&mut self, env : &mut Env) -> R<Pause<T>> {
+        let depth = self.vm.depth();
+        self.run_until(env, |d| d < depth)
+    }
+
+    fn run_until(&mut self, env : &mut Env, done : impl Fn(usize) -> bool) -> R<Pause<T>> {
+        self.started = true;
+        loop {
+            if !self.watchpoints.is_empty() {
+                self.vm.start_journal();
+            }
+            let status = self.vm.step(env);
+            let written = self.vm.take_journal();
+            match status? {
+                Status::Running => { },
+                Status::Finished(ret) => return Ok(Pause::Finished(ret)),
+                Status::Pending => return Ok(Pause::Pending),
+                Status::OutOfFuel => return Ok(Pause::OutOfFuel),
+            }
+
+            if let Some(pause) = self.watchpoint(&written) {
+                return Ok(pause);
+            }
+            // NOTE:  Leave functions which ran off their end now, so that the caller's next
+            // instruction is checked for a breakpoint before it runs.
+            if !self.vm.leave_ended_functions() {
+                return Ok(Pause::Finished(self.vm.ret().cloned()));
+            }
+            if let Some(pause) = self.breakpoint() {
+                return Ok(pause);
+            }
+            if done(self.vm.depth()) {
+                return Ok(Pause::Step);
+            }
+        }
+    }
+
+    fn breakpoint(&self) -> Option<Pause<T>> {
+        let func = self.vm.current_function();
+        let index = self.vm.instr_ptr();
+        if self.breakpoints.contains(&(func, index)) {
+            Some(Pause::Breakpoint { func, index })
+        }
+        else {
+            None
+        }
+    }
+
+    fn watchpoint(&self, written : &[(Func, Symbol)]) -> Option<Pause<T>> {
+        written.iter()
+               .find(|write| self.watchpoints.contains(write))
+               .map(|(func, sym)| Pause::Watchpoint { func: *func, sym: *sym })
+    }
+
+    fn source(&self, func : Func) -> R<&'a [Instr<T, Env>]> {
+        match self.vm.program().source(&func) {
+            Some(source) => Ok(source),
+            None => Err(Box::new(VmError::FunctionDoesNotExist(func.0))),
+        }
+    }
+}
+
+fn first_executed<T : Clone, Env>( source : &[Instr<T, Env>], index : usize ) -> usize {
+    let skipped = source.iter().skip(index).take_while(|instr| matches!(instr, Instr::Label(_))).count();
+    index + skipped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::compile::compile;
+
+    fn program() -> HashMap<Func, Vec<Instr<u8, ()>>> {
+        HashMap::from(
+            [(Func(0), vec![ Instr::LoadValue(Symbol(0), 1)
+                           , Instr::LoadFunc(Symbol(1), Func(1))
+                           , Instr::Call(Symbol(1))
+                           , Instr::LoadFromReturn(Symbol(0))
+                           , Instr::Return(Symbol(0))
+                           ])
+            ,(Func(1), vec![ Instr::LoadValue(Symbol(2), 5)
+                           , Instr::Label(Label(0))
+                           , Instr::SysCall(Box::new(|locals, _| locals.set(&Symbol(3), Data::Value(7))))
+                           , Instr::Return(Symbol(2))
+                           ])
+            ])
+    }
+
+    #[test]
+    fn should_stop_at_label_breakpoint_and_show_frames() -> R<()> {
+        let func_defs = program();
+        let program = compile(&func_defs)?;
+        let mut debugger = Debugger::new(Vm::new(&program)?);
+
+        assert_eq!( debugger.set_label_breakpoint(Func(1), Label(0))?, 2 );
+        assert!( matches!( debugger.run(&mut ())?, Pause::Breakpoint { func: Func(1), index: 2 } ) );
+
+        let frames = debugger.frames();
+        assert_eq!( frames.iter().map(|frame| (frame.func, frame.instr_ptr)).collect::<Vec<_>>()
+                  , vec![(Func(1), 2), (Func(0), 2)]
+                  );
+        assert!( matches!( frames[0].locals.get(&Symbol(2))?, Data::Value(5) ) );
+        assert!( matches!( frames[1].locals.get(&Symbol(0))?, Data::Value(1) ) );
+
+        assert!( matches!( debugger.run(&mut ())?, Pause::Finished(Some(Data::Value(5))) ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_step_into_over_and_out_of_calls() -> R<()> {
+        let func_defs = program();
+        let program = compile(&func_defs)?;
+        let mut debugger = Debugger::new(Vm::new(&program)?);
+
+        debugger.step_into(&mut ())?;
+        debugger.step_into(&mut ())?;
+        assert!( matches!( debugger.step_over(&mut ())?, Pause::Step ) );
+        assert_eq!( (debugger.vm().current_function(), debugger.vm().instr_ptr()), (Func(0), 3) );
+
+        let mut debugger = Debugger::new(Vm::new(&program)?);
+        debugger.set_breakpoint(Func(0), 0)?;
+        assert!( matches!( debugger.run(&mut ())?, Pause::Breakpoint { func: Func(0), index: 0 } ) );
+        debugger.step_into(&mut ())?;
+        debugger.step_into(&mut ())?;
+        assert!( matches!( debugger.step_into(&mut ())?, Pause::Step ) );
+        assert_eq!( (debugger.vm().current_function(), debugger.vm().instr_ptr(), debugger.vm().depth()), (Func(1), 0, 1) );
+
+        assert!( matches!( debugger.step_out(&mut ())?, Pause::Step ) );
+        assert_eq!( (debugger.vm().current_function(), debugger.vm().instr_ptr(), debugger.vm().depth()), (Func(0), 3, 0) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_stop_when_watched_symbol_is_written() -> R<()> {
+        let func_defs = program();
+        let program = compile(&func_defs)?;
+        let mut debugger = Debugger::new(Vm::new(&program)?);
+        debugger.watch(Func(1), Symbol(3));
+        debugger.watch(Func(0), Symbol(0));
+
+        assert!( matches!( debugger.run(&mut ())?, Pause::Watchpoint { func: Func(0), sym: Symbol(0) } ) );
+        assert!( matches!( debugger.run(&mut ())?, Pause::Watchpoint { func: Func(1), sym: Symbol(3) } ) );
+        assert_eq!( debugger.vm().instr_ptr(), 3 );
+
+        assert!( matches!( debugger.run(&mut ())?, Pause::Watchpoint { func: Func(0), sym: Symbol(0) } ) );
+        assert!( matches!( debugger.frames()[0].locals.get(&Symbol(0))?, Data::Value(5) ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_stop_each_time_watched_symbol_is_written_with_same_data() -> R<()> {
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadValue(Symbol(0), 1)
+                           , Instr::LoadValue(Symbol(0), 1)
+                           , Instr::SysCall(Box::new(|locals, _| locals.set(&Symbol(0), Data::Value(1))))
+                           , Instr::Return(Symbol(0))
+                           ])
+            ]);
+        let program = compile(&func_defs)?;
+        let mut debugger = Debugger::new(Vm::new(&program)?);
+        debugger.watch(Func(0), Symbol(0));
+
+        for index in 1..4 {
+            assert!( matches!( debugger.run(&mut ())?, Pause::Watchpoint { func: Func(0), sym: Symbol(0) } ) );
+            assert_eq!( debugger.vm().instr_ptr(), index );
+        }
+        assert!( matches!( debugger.run(&mut ())?, Pause::Finished(Some(Data::Value(1))) ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_missing_label() -> R<()> {
+        let func_defs = program();
+        let program = compile(&func_defs)?;
+        let mut debugger = Debugger::new(Vm::new(&program)?);
+
+        let error = debugger.set_label_breakpoint(Func(0), Label(4)).unwrap_err();
+        assert!( matches!( error.downcast_ref::<VmError>(), Some(VmError::LabelDoesNotExist { func: 0, label: 4 }) ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_breakpoint_past_last_instruction() -> R<()> {
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadValue(Symbol(0), 1)
+                           , Instr::Label(Label(0))
+                           ])
+            ]);
+        let program = compile(&func_defs)?;
+        let mut debugger = Debugger::new(Vm::new(&program)?);
+
+        let error = debugger.set_breakpoint(Func(0), 7).unwrap_err();
+        assert!( matches!( error.downcast_ref::<VmError>(), Some(VmError::InstructionDoesNotExist { func: 0, index: 7 }) ) );
+
+        let error = debugger.set_label_breakpoint(Func(0), Label(0)).unwrap_err();
+        assert!( matches!( error.downcast_ref::<VmError>(), Some(VmError::InstructionDoesNotExist { func: 0, index: 1 }) ) );
+        assert_eq!( debugger.breakpoints().count(), 0 );
+        Ok(())
+    }
+}
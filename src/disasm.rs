@@ -0,0 +1,208 @@
+
+//! Renders programs in the textual assembly accepted by `asm::parse`.
+//!
+//! Closures carry no name of their own, so closure bearing instructions print the registry name
+//! found in the native names table.  Instructions without an entry print the `<native>`
+//! placeholder, which `asm::parse` will refuse.
+//!
+//! With debug info, function headers carry their names, symbol and label names are written as
+//! `name` directives and each instruction is followed by a comment with its source span.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Write};
+
+use crate::asm::Assembly;
+use crate::data::*;
+use crate::debug::DebugInfo;
+
+pub const NATIVE_PLACEHOLDER : &str = "<native>";
+
+pub fn disassemble<T : Clone + Display, Env>( func_defs : &HashMap<Func, Vec<Instr<T, Env>>>
+                                            , native_names : Option<&HashMap<(Func, usize), String>>
+                                            ) -> String {
+    disassemble_with_debug_info(func_defs, native_names, None)
+}
+
+pub fn disassemble_with_debug_info<T : Clone + Display, Env>( func_defs : &HashMap<Func, Vec<Instr<T, Env>>>
+                                                            , native_names : Option<&HashMap<(Func, usize), String>>
+                                                            , debug_info : Option<&DebugInfo>
+                                                            ) -> String {
+
+    let mut funcs = func_defs.keys().copied().collect::<Vec<_>>();
+    funcs.sort_by_key(|func| func.0);
+
+    let mut output = String::new();
+    for func in funcs {
+        // NOTE:  Writing into a String cannot fail.
+        match debug_info.and_then(|info| info.func_name(&func)) {
+            Some(name) => writeln!(output, "func {} {}:", func.0, name).unwrap(),
+            None => writeln!(output, "func {}:", func.0).unwrap(),
+        }
+        if let Some(info) = debug_info {
+            write_names(&mut output, info, func);
+        }
+        for (index, instr) in func_defs[&func].iter().enumerate() {
+            let name = native_names.and_then(|names| names.get(&(func, index))).map(|name| name.as_str());
+            match debug_info.and_then(|info| info.span(&func, index)) {
+                Some(span) => writeln!(output, "    {}  # {}", disassemble_instr(instr, name), span).unwrap(),
+                None => writeln!(output, "    {}", disassemble_instr(instr, name)).unwrap(),
+            }
+        }
+    }
+    output
+}
+
+pub fn disassemble_assembly<T : Clone + Display, Env>( assembly : &Assembly<T, Env> ) -> String {
+    disassemble(&assembly.func_defs, Some(&assembly.native_names))
+}
+
+fn write_names( output : &mut String, debug_info : &DebugInfo, func : Func ) {
+    let mut symbols = debug_info.symbol_names.iter().filter(|((f, _), _)| *f == func).collect::<Vec<_>>();
+    symbols.sort_by_key(|((_, sym), _)| sym.0);
+    for ((_, sym), name) in symbols {
+        writeln!(output, "    name {} {}", sym, name).unwrap();
+    }
+
+    let mut labels = debug_info.label_names.iter().filter(|((f, _), _)| *f == func).collect::<Vec<_>>();
+    labels.sort_by_key(|((_, label), _)| label.0);
+    for ((_, label), name) in labels {
+        writeln!(output, "    name {} {}", label, name).unwrap();
+    }
+}
+
+pub fn disassemble_instr<T : Clone + Display, Env>( instr : &Instr<T, Env>, native_name : Option<&str> ) -> String {
+    let native = native_name.unwrap_or(NATIVE_PLACEHOLDER);
+    match instr {
+        Instr::Label(label) => format!("label {}", label),
+        Instr::Jump(label) => format!("jump {}", label),
+        Instr::BranchOnTrue(label, _) => format!("branch_on_true {} {}", label, native),
+        Instr::Return(sym) => format!("return {}", sym),
+        Instr::LoadValue(sym, value) => format!("load_value {} {}", sym, value),
+        Instr::LoadFromReturn(sym) => format!("load_from_return {}", sym),
+        Instr::PushParam(sym) => format!("push_param {}", sym),
+        Instr::PopParam(sym) => format!("pop_param {}", sym),
+        Instr::LoadFromExec(sym, _) => format!("load_from_exec {} {}", sym, native),
+        Instr::LoadFunc(sym, func) => format!("load_func {} {}", sym, func),
+        Instr::LoadNative(sym, native) => format!("load_native {} {}", sym, native),
+        Instr::Call(sym) => format!("call {}", sym),
+        Instr::TailCall(sym) => format!("tail_call {}", sym),
+        Instr::Yield(sym) => format!("yield {}", sym),
+        Instr::Resume(sym) => format!("resume {}", sym),
+        Instr::MakeClosure(sym, func, captures) =>
+            captures.iter().fold(format!("make_closure {} {}", sym, func), |s, c| format!("{} {}", s, c)),
+        Instr::Apply(sym, func, args) =>
+            args.iter().fold(format!("apply {} {}", sym, func), |s, a| format!("{} {}", s, a)),
+        Instr::Try(label) => format!("try {}", label),
+        Instr::EndTry => "end_try".to_string(),
+        Instr::Throw(sym) => format!("throw {}", sym),
+        Instr::Catch(sym) => format!("catch {}", sym),
+        Instr::SysCall(_) => format!("sys_call {}", native),
+        Instr::LoadFromSysCall(sym, _) => format!("load_from_sys_call {} {}", sym, native),
+        Instr::LoadFromPollSysCall(sym, _) => format!("load_from_poll_sys_call {} {}", sym, native),
+        Instr::LoadFromAsyncSysCall(sym, _) => format!("load_from_async_sys_call {} {}", sym, native),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::parse;
+    use crate::registry::Registry;
+    use crate::R;
+
+    #[test]
+    fn should_round_trip_through_parser() -> R<()> {
+        let mut registry : Registry<u8, ()> = Registry::new();
+        registry.register_branch("never", |_| Ok(false));
+        registry.register_exec("one", |_| Ok(Data::Value(1)));
+        registry.register_sys_call("nop", |_, _| Ok(()));
+        registry.register_load_sys_call("two", |_, _| Ok(Data::Value(2)));
+        registry.register_poll_sys_call("later", |_, _| Ok(std::task::Poll::Pending));
+        registry.register_async_sys_call("fetch", |_, _| Box::pin(async { Ok(Data::Value(3)) }));
+
+        let input = "\
+func 0:
+    load_value s0 5
+    push_param s0
+    load_func s1 f2
+    load_native s5 n3
+    call s1
+    resume s1
+    make_closure s2 f2 s0 s1
+    make_closure s3 f2
+    apply s4 s1 s0 s2
+    load_from_return s0
+    return s0
+func 2:
+    pop_param s3
+    yield s3
+    label l0
+    branch_on_true l0 never
+    load_from_exec s1 one
+    sys_call nop
+    load_from_sys_call s2 two
+    load_from_poll_sys_call s2 later
+    load_from_async_sys_call s2 fetch
+    jump l1
+    label l1
+    try l2
+    throw s1
+    end_try
+    label l2
+    catch s1
+    tail_call s4
+";
+
+        let assembly = parse(input, &registry)?;
+        let output = disassemble_assembly(&assembly);
+
+        assert_eq!( output, input );
+        Ok(())
+    }
+
+    #[test]
+    fn should_write_names_and_spans_from_debug_info() -> R<()> {
+        let registry : Registry<u8, ()> = Registry::new();
+        let input = "\
+func 0 main:
+    name s0 total
+    name l0 done
+    load_value s0 5; jump l0
+    label l0
+    return s0
+";
+
+        let assembly = parse(input, &registry)?;
+        let output = disassemble_with_debug_info(&assembly.func_defs, Some(&assembly.native_names), Some(&assembly.debug_info));
+
+        assert_eq!( output, "\
+func 0 main:
+    name s0 total
+    name l0 done
+    load_value s0 5  # 4:5
+    jump l0  # 4:22
+    label l0  # 5:5
+    return s0  # 6:5
+" );
+
+        let reparsed = parse(&output, &registry)?;
+        assert_eq!( reparsed.debug_info.func_name(&Func(0)), Some("main") );
+        assert_eq!( reparsed.debug_info.symbol_name(&Func(0), &Symbol(0)), Some("total") );
+        assert_eq!( reparsed.debug_info.label_name(&Func(0), &Label(0)), Some("done") );
+        Ok(())
+    }
+
+    #[test]
+    fn should_use_placeholder_for_unnamed_natives() {
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::SysCall(Box::new(|_, _| Ok(())))
+                           , Instr::LoadFromExec(Symbol(1), Box::new(|_| Ok(Data::Value(1))))
+                           , Instr::Return(Symbol(1))
+                           ])
+            ]);
+
+        let output = disassemble(&func_defs, None);
+
+        assert_eq!( output, "func 0:\n    sys_call <native>\n    load_from_exec s1 <native>\n    return s1\n" );
+    }
+}
@@ -1,29 +1,287 @@
+use crate::data::{Func, Label, Symbol};
+use crate::debug::{DebugInfo, Span};
+
 #[derive(Debug)]
 pub enum VmError {
     FunctionDoesNotExist(usize),
+    NativeDoesNotExist(usize),
     SymbolDoesNotExist { func : usize, sym : usize },
+    SymbolIsNotValue { func : usize, sym : usize },
     RedefinitionOfLabel { func : usize, label : usize },
     LabelDoesNotExist { func : usize, label : usize },
+    InstructionDoesNotExist { func : usize, index : usize },
     ReturnNotSet { func : usize, sym : usize },
     AttemptToCallNonFunction { current_func : usize },
+    AttemptToApplyNonFunction { current_func : usize },
     AttemptToPopEmptyParams { current_func : usize, sym : usize },
+    StackOverflow { func : usize, depth : usize },
+    ParamStackOverflow { func : usize, depth : usize },
+    LocalsOverflow { func : usize, count : usize },
+    UncaughtException { func : usize },
+    EndTryWithoutTry { func : usize },
+    ExceptionNotSet { func : usize, sym : usize },
+    YieldOutsideCoroutine { func : usize },
+    AttemptToResumeNonCoroutine { current_func : usize },
+    ResumeOfFinishedCoroutine { func : usize, sym : usize },
+    PendingSysCall { func : usize },
+    NoPendingSysCall { func : usize },
+    ReplayDiverged { func : usize },
 }
 
-impl std::fmt::Display for VmError {
-    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+impl VmError {
+    /// Displays the error using the names from the debug info wherever it has them.
+    pub fn with_debug_info<'a>(&'a self, debug_info : &'a DebugInfo) -> NamedVmError<'a> {
+        NamedVmError { error: self, debug_info }
+    }
+
+    fn describe(&self, f : &mut std::fmt::Formatter, debug_info : Option<&DebugInfo>) -> std::fmt::Result {
+        let default = DebugInfo::default();
+        let names = debug_info.unwrap_or(&default);
+        let func_name = |func : &usize| names.describe_func(&Func(*func));
+        let sym_name = |func : &usize, sym : &usize| names.describe_symbol(&Func(*func), &Symbol(*sym));
+        let label_name = |func : &usize, label : &usize| names.describe_label(&Func(*func), &Label(*label));
+
         match self {
-            VmError::SymbolDoesNotExist { func, sym } => write!(f, "symbol {} does not exist for function {}", sym, func),
-            VmError::FunctionDoesNotExist(func) => write!(f, "symbol does not exist:  {}", func),
-            VmError::RedefinitionOfLabel { func, label } => write!(f, "redefinition of label {} in function {}", label, func),
-            VmError::LabelDoesNotExist { func, label } => write!(f, "label {} does not exist in function {}", label, func),
+            VmError::SymbolDoesNotExist { func, sym } =>
+                write!(f, "symbol {} does not exist for function {}", sym_name(func, sym), func_name(func)),
+            VmError::SymbolIsNotValue { func, sym } =>
+                write!(f, "symbol {} does not hold a value in function {}", sym_name(func, sym), func_name(func)),
+            VmError::FunctionDoesNotExist(func) => write!(f, "symbol does not exist:  {}", func_name(func)),
+            VmError::NativeDoesNotExist(native) => write!(f, "native function does not exist:  {}", native),
+            VmError::RedefinitionOfLabel { func, label } =>
+                write!(f, "redefinition of label {} in function {}", label_name(func, label), func_name(func)),
+            VmError::LabelDoesNotExist { func, label } =>
+                write!(f, "label {} does not exist in function {}", label_name(func, label), func_name(func)),
+            VmError::InstructionDoesNotExist { func, index } =>
+                write!(f, "instruction {} does not exist in function {}", index, func_name(func)),
             VmError::ReturnNotSet { func, sym } => 
-                write!(f, "return not set in function {} for set into symbol {}", func, sym),
+                write!(f, "return not set in function {} for set into symbol {}", func_name(func), sym_name(func, sym)),
             VmError::AttemptToCallNonFunction { current_func } => 
-                write!(f, "attempt to call non-function in function {}", current_func),
+                write!(f, "attempt to call non-function in function {}", func_name(current_func)),
+            VmError::AttemptToApplyNonFunction { current_func } => 
+                write!(f, "attempt to apply non-function in function {}", func_name(current_func)),
             VmError::AttemptToPopEmptyParams { current_func, sym } =>
-                write!(f, "attempt to pop empty params in function {} into symbol {}", current_func, sym),
+                write!(f, "attempt to pop empty params in function {} into symbol {}", func_name(current_func), sym_name(current_func, sym)),
+            VmError::StackOverflow { func, depth } =>
+                write!(f, "stack overflow calling function {} at depth {}", func_name(func), depth),
+            VmError::ParamStackOverflow { func, depth } =>
+                write!(f, "param stack overflow in function {} at depth {}", func_name(func), depth),
+            VmError::LocalsOverflow { func, count } =>
+                write!(f, "locals overflow in function {} with {} locals", func_name(func), count),
+            VmError::UncaughtException { func } =>
+                write!(f, "uncaught exception thrown in function {}", func_name(func)),
+            VmError::EndTryWithoutTry { func } =>
+                write!(f, "end of try region without matching try in function {}", func_name(func)),
+            VmError::ExceptionNotSet { func, sym } =>
+                write!(f, "exception not set in function {} for set into symbol {}", func_name(func), sym_name(func, sym)),
+            VmError::YieldOutsideCoroutine { func } =>
+                write!(f, "yield outside of coroutine in function {}", func_name(func)),
+            VmError::AttemptToResumeNonCoroutine { current_func } =>
+                write!(f, "attempt to resume non-coroutine in function {}", func_name(current_func)),
+            VmError::ResumeOfFinishedCoroutine { func, sym } =>
+                write!(f, "resume of finished coroutine in symbol {} in function {}", sym_name(func, sym), func_name(func)),
+            VmError::PendingSysCall { func } =>
+                write!(f, "sys call pending in function {} but the vm cannot wait for it", func_name(func)),
+            VmError::NoPendingSysCall { func } =>
+                write!(f, "attempt to complete sys call in function {} when none is pending", func_name(func)),
+            VmError::ReplayDiverged { func } =>
+                write!(f, "replay diverged from the log in function {}", func_name(func)),
+        }
+    }
+}
+
+impl std::fmt::Display for VmError {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.describe(f, None)
+    }
+}
+
+pub struct NamedVmError<'a> {
+    error : &'a VmError,
+    debug_info : &'a DebugInfo,
+}
+
+impl<'a> std::fmt::Display for NamedVmError<'a> {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.error.describe(f, Some(self.debug_info))
+    }
+}
+
+impl std::error::Error for VmError {}
+
+#[derive(Debug)]
+pub enum ParseError {
+    UnexpectedEnd { line : usize, column : usize, expected : &'static str },
+    UnexpectedToken { line : usize, column : usize, found : String, expected : &'static str },
+    UnknownInstruction { line : usize, column : usize, name : String },
+    UnknownNative { line : usize, column : usize, name : String },
+    InvalidValue { line : usize, column : usize, text : String },
+    InstructionOutsideFunction { line : usize, column : usize },
+    RedefinitionOfFunction { line : usize, column : usize, func : usize },
+}
+
+impl ParseError {
+    pub fn line(&self) -> usize {
+        match self {
+            ParseError::UnexpectedEnd { line, .. } => *line,
+            ParseError::UnexpectedToken { line, .. } => *line,
+            ParseError::UnknownInstruction { line, .. } => *line,
+            ParseError::UnknownNative { line, .. } => *line,
+            ParseError::InvalidValue { line, .. } => *line,
+            ParseError::InstructionOutsideFunction { line, .. } => *line,
+            ParseError::RedefinitionOfFunction { line, .. } => *line,
+        }
+    }
+
+    pub fn column(&self) -> usize {
+        match self {
+            ParseError::UnexpectedEnd { column, .. } => *column,
+            ParseError::UnexpectedToken { column, .. } => *column,
+            ParseError::UnknownInstruction { column, .. } => *column,
+            ParseError::UnknownNative { column, .. } => *column,
+            ParseError::InvalidValue { column, .. } => *column,
+            ParseError::InstructionOutsideFunction { column, .. } => *column,
+            ParseError::RedefinitionOfFunction { column, .. } => *column,
         }
     }
 }
 
-impl std::error::Error for VmError {}
\ No newline at end of file
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}: ", self.line(), self.column())?;
+        match self {
+            ParseError::UnexpectedEnd { expected, .. } => write!(f, "expected {} but found end of statement", expected),
+            ParseError::UnexpectedToken { found, expected, .. } => write!(f, "expected {} but found '{}'", expected, found),
+            ParseError::UnknownInstruction { name, .. } => write!(f, "unknown instruction '{}'", name),
+            ParseError::UnknownNative { name, .. } => write!(f, "unknown native function '{}'", name),
+            ParseError::InvalidValue { text, .. } => write!(f, "invalid value '{}'", text),
+            ParseError::InstructionOutsideFunction { .. } => write!(f, "instruction outside of function"),
+            ParseError::RedefinitionOfFunction { func, .. } => write!(f, "redefinition of function {}", func),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug)]
+pub enum SerialError {
+    BadMagic,
+    UnsupportedVersion { found : u16, expected : u16 },
+    ChecksumMismatch { found : u32, expected : u32 },
+    UnexpectedEnd,
+    TrailingBytes(usize),
+    InvalidOpcode(u8),
+    InvalidValue(&'static str),
+    InvalidNativeIndex(usize),
+    UnknownNative(String),
+    MissingNativeName { func : usize, index : usize },
+    DuplicateFunction(usize),
+}
+
+impl std::fmt::Display for SerialError {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SerialError::BadMagic => write!(f, "input does not start with the expected magic bytes"),
+            SerialError::UnsupportedVersion { found, expected } =>
+                write!(f, "unsupported format version {} (expected {})", found, expected),
+            SerialError::ChecksumMismatch { found, expected } =>
+                write!(f, "checksum mismatch: found {:08x} but expected {:08x}", found, expected),
+            SerialError::UnexpectedEnd => write!(f, "unexpected end of input"),
+            SerialError::TrailingBytes(count) => write!(f, "{} unexpected bytes after program", count),
+            SerialError::InvalidOpcode(opcode) => write!(f, "invalid opcode {}", opcode),
+            SerialError::InvalidValue(kind) => write!(f, "invalid encoding for {}", kind),
+            SerialError::InvalidNativeIndex(index) => write!(f, "native table index {} is out of range", index),
+            SerialError::UnknownNative(name) => write!(f, "unknown native function '{}'", name),
+            SerialError::MissingNativeName { func, index } =>
+                write!(f, "instruction {} in function {} has no native name", index, func),
+            SerialError::DuplicateFunction(func) => write!(f, "function {} is defined more than once", func),
+        }
+    }
+}
+
+impl std::error::Error for SerialError {}
+
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub func : usize,
+    /// None when the problem is not tied to a single instruction.
+    pub index : Option<usize>,
+    pub error : VmError,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.index {
+            Some(index) => write!(f, "function {} instruction {}: {}", self.func, index, self.error),
+            None => write!(f, "function {}: {}", self.func, self.error),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct VerifyError {
+    pub diagnostics : Vec<Diagnostic>,
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "program failed verification with {} problem(s)", self.diagnostics.len())?;
+        for diagnostic in &self.diagnostics {
+            write!(f, "\n  {}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+#[derive(Debug, Clone)]
+pub struct TraceFrame {
+    pub func : usize,
+    /// Index of the source instruction that was executing, or the call site for callers.
+    pub instr_ptr : usize,
+    pub name : Option<String>,
+    pub span : Option<Span>,
+}
+
+impl std::fmt::Display for TraceFrame {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "at {} (function {}) instruction {}", name, self.func, self.instr_ptr)?,
+            None => write!(f, "at function {} instruction {}", self.func, self.instr_ptr)?,
+        }
+        if let Some(span) = self.span {
+            write!(f, " ({})", span)?;
+        }
+        Ok(())
+    }
+}
+
+/// An error raised while running a program along with the frames that were active, innermost
+/// first.
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub error : Box<dyn std::error::Error>,
+    pub trace : Vec<TraceFrame>,
+    /// The error described with the program's debug names, when it had debug info.
+    pub message : Option<String>,
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.message {
+            Some(message) => write!(f, "{}", message)?,
+            None => write!(f, "{}", self.error)?,
+        }
+        for frame in &self.trace {
+            write!(f, "\n  {}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.error.as_ref())
+    }
+}
@@ -3,183 +3,124 @@ use std::collections::HashMap;
 
 pub mod error;
 pub mod data;
+pub mod vm;
+pub mod compile;
+pub mod debug;
+pub mod trace;
+pub mod debugger;
+pub mod profile;
+pub mod coverage;
+pub mod registry;
+pub mod asm;
+pub mod disasm;
+pub mod serial;
+pub mod replay;
+pub mod verify;
 
-use crate::error::VmError;
 use crate::data::*;
+use crate::error::{RuntimeError, VmError};
+use crate::vm::{Vm, VmLimits, Status};
+use crate::compile::{compile, CompiledProgram};
+use crate::trace::{Tracer, NoTracer};
+use crate::replay::SysCallLog;
 
 type R<T> = Result<T, Box<dyn std::error::Error>>;
 
-struct Frame<T : Clone> {
-    instr_ptr : usize,
-    locals : Locals<T>,
-    current_function : Func,
+pub fn run<T : Clone, Env>( func_defs : &HashMap<Func, Vec<Instr<T, Env>>>, env: &mut Env ) -> R<Option<Data<T>>> {
+    run_compiled(&compile(func_defs)?, env)
 }
 
-struct FuncDefWithLabel<'a, T : Clone, Env> {
-    pub body : &'a Vec<Instr<T, Env>>,
-    pub label_map : HashMap<Label, usize>,
+/// Errors raised while the program runs are wrapped in a RuntimeError carrying the stack trace.
+pub fn run_compiled<T : Clone, Env>( program : &CompiledProgram<T, Env>, env: &mut Env ) -> R<Option<Data<T>>> {
+    run_compiled_traced(program, env, NoTracer)
 }
 
-pub fn run<T : Clone, Env>( func_defs : &HashMap<Func, Vec<Instr<T, Env>>>, env: &mut Env ) -> R<Option<Data<T>>> {
-
-    let mut current_function : Func = Func(0);
-
-    if !func_defs.contains_key(&current_function) {
-        return Err(Box::new(VmError::FunctionDoesNotExist(0)));
-    }
+/// Like `run`, but reports the execution to the tracer.  Pass `&mut tracer` to keep it.
+pub fn run_traced<T : Clone, Env, Tr : Tracer<T, Env>>( func_defs : &HashMap<Func, Vec<Instr<T, Env>>>, env: &mut Env, tracer : Tr ) -> R<Option<Data<T>>> {
+    run_compiled_traced(&compile(func_defs)?, env, tracer)
+}
 
-    let func_defs_with_label = func_defs.iter()
-                                        .map(|kvp| Ok((*kvp.0, setup_label_map(kvp.1, *kvp.0)?)))
-                                        .collect::<R<HashMap<_, _>>>()?;
+pub fn run_compiled_traced<T : Clone, Env, Tr : Tracer<T, Env>>( program : &CompiledProgram<T, Env>, env: &mut Env, tracer : Tr ) -> R<Option<Data<T>>> {
+    let mut vm = Vm::with_tracer(program, VmLimits::default(), tracer)?;
+    finish(program, &mut vm, env)
+}
 
-    let mut stack : Vec<Frame<T>> = vec![];
-    // NOTE:  We checked that Func(0) is present already.
-    let mut instrs : &Vec<Instr<T, Env>> = &func_defs_with_label.get(&current_function).unwrap().body;
-    let mut label_map : &HashMap<Label, usize> = &func_defs_with_label.get(&current_function).unwrap().label_map;
-    let mut instr_ptr = 0;
-    let mut locals : Locals<T> = Locals::new(current_function.0);
-    let mut params : Vec<Data<T>> = vec![];
-    let mut ret = None;
+/// Like `run`, but logs branch decisions and host results into the log, which is filled even
+/// when the run fails.
+pub fn run_recorded<T : Clone, Env>( func_defs : &HashMap<Func, Vec<Instr<T, Env>>>, env: &mut Env, log : &mut SysCallLog<T> ) -> R<Option<Data<T>>> {
+    run_compiled_recorded(&compile(func_defs)?, env, log)
+}
 
-    loop {
+pub fn run_compiled_recorded<T : Clone, Env>( program : &CompiledProgram<T, Env>, env: &mut Env, log : &mut SysCallLog<T> ) -> R<Option<Data<T>>> {
+    let mut vm = Vm::new(program)?;
+    vm.start_recording();
+    let result = finish(program, &mut vm, env);
+    // NOTE:  Recording was started above.
+    *log = vm.take_recording().unwrap();
+    result
+}
 
-        if instrs.len() <= instr_ptr {
-            if stack.len() == 0 {
-                break;
-            }
+/// Like `run`, but branch decisions and host results come from a log made by `run_recorded`.
+pub fn run_replayed<T : Clone, Env>( func_defs : &HashMap<Func, Vec<Instr<T, Env>>>, env: &mut Env, log : SysCallLog<T> ) -> R<Option<Data<T>>> {
+    run_compiled_replayed(&compile(func_defs)?, env, log)
+}
 
-            Frame { instr_ptr, locals, current_function } = stack.pop().unwrap();
-            // NOTE:  We don't have to check if current_function exists because if we're poping
-            // then we must have called it previously.
-            instrs = &func_defs_with_label.get(&current_function).unwrap().body;
-            label_map = &func_defs_with_label.get(&current_function).unwrap().label_map;
-        }
-
-        match &instrs[instr_ptr] {
-            Instr::Label(_) => { instr_ptr += 1; },
-            Instr::Jump(label) =>
-                match label_map.get(label) {
-                    Some(ptr) => instr_ptr = *ptr,
-                    None => return Err(Box::new(VmError::LabelDoesNotExist {label : label.0, func : current_function.0})),
-                },
-            Instr::BranchOnTrue(label, f) => {
-                if f(&locals)? {
-                    match label_map.get(label) {
-                        Some(ptr) => instr_ptr = *ptr,
-                        None => return Err(Box::new(VmError::LabelDoesNotExist {label : label.0, func : current_function.0})),
-                    }
-                }
-                else {
-                    instr_ptr += 1;
-                }
-            },
-            Instr::Return(sym) => {
-                ret = Some(locals.get(sym)?);
-
-                if stack.len() == 0 {
-                    break;
-                }
-
-                Frame { instr_ptr, locals, current_function } = stack.pop().unwrap();
-                // NOTE:  We don't have to check if current_function exists because if we're poping
-                // then we must have called it previously.
-                instrs = &func_defs_with_label.get(&current_function).unwrap().body;
-                label_map = &func_defs_with_label.get(&current_function).unwrap().label_map;
-            },
-            Instr::LoadValue(sym, data) => {
-                locals.set(sym, Data::Value(data.clone()))?;
-                instr_ptr += 1;
-            },
-            Instr::LoadFromReturn(sym) => {
-                match ret {
-                    Some(ref ret) => {
-                        locals.set(sym, ret.clone())?;
-                        instr_ptr += 1;
-                    },
-                    None => return Err(Box::new(VmError::ReturnNotSet { func: current_function.0, sym: sym.0 })),
-                }
-            },
-            Instr::Call(sym) => {
-                match locals.get(sym)? {
-                    Data::Func(f) => {
-
-                        if !func_defs_with_label.contains_key(&f) {
-                            return Err(Box::new(VmError::FunctionDoesNotExist(f.0)));
-                        }
-
-                        let old_function = current_function;
-                        let old_instr_ptr = instr_ptr + 1;
-                        let mut old_locals : Locals<T> = Locals::new(f.0);
-
-                        current_function = f;
-                        instr_ptr = 0;
-                        // NOTE:  We check that the function is here earlier on in this case.
-                        instrs = &func_defs_with_label.get(&current_function).unwrap().body;
-                        label_map = &func_defs_with_label.get(&current_function).unwrap().label_map;
-                        std::mem::swap(&mut old_locals, &mut locals);
-
-                        stack.push(Frame { instr_ptr: old_instr_ptr
-                                         , locals: old_locals
-                                         , current_function: old_function
-                                         });
-                    },
-                    _ => return Err(Box::new(VmError::AttemptToCallNonFunction { current_func: current_function.0 })),
-                }
+pub fn run_compiled_replayed<T : Clone, Env>( program : &CompiledProgram<T, Env>, env: &mut Env, log : SysCallLog<T> ) -> R<Option<Data<T>>> {
+    let mut vm = Vm::new(program)?;
+    vm.replay(log);
+    finish(program, &mut vm, env)
+}
 
-            },
-            Instr::PushParam(sym) => {
-                params.push(locals.get(sym)?);
-                instr_ptr += 1;
-            },
-            Instr::PopParam(sym) => {
-                match params.pop() {
-                    Some(param) => locals.set(sym, param)?,
-                    None => return Err(Box::new(VmError::AttemptToPopEmptyParams { current_func: current_function.0, sym: sym.0 })),
-                }
-                instr_ptr += 1;
-            },
-            Instr::LoadFromExec(sym, f) => {
-                locals.set(sym, f(&locals)?)?;
-                instr_ptr += 1;
-            },
-            Instr::LoadFunc(sym, f) => {
-                locals.set(sym, Data::Func(*f))?;
-                instr_ptr += 1;
-            },
-            Instr::SysCall(f) => {
-                f(&mut locals, env)?;
-                instr_ptr += 1;
-            },
-            Instr::LoadFromSysCall(sym, f) => {
-                let result = f(&mut locals, env)?;
-                locals.set(sym, result)?;
-                instr_ptr += 1;
-            },
-        }
+fn finish<T : Clone, Env, Tr : Tracer<T, Env>>( program : &CompiledProgram<T, Env>, vm : &mut Vm<T, Env, Tr>, env: &mut Env ) -> R<Option<Data<T>>> {
+    let status = vm.run_to_completion(env).and_then(|status| match status {
+        // NOTE:  Nothing can complete the sys call once we return, so it is an error here.
+        Status::Pending => Err(VmError::PendingSysCall { func: vm.current_function().0 }.into()),
+        status => Ok(status),
+    }).map_err(|error| runtime_error(program, vm, error))?;
+    match status {
+        Status::Finished(ret) => Ok(ret),
+        // NOTE:  The vm is not metered here, so it can only stop by finishing.
+        _ => unreachable!(),
     }
-
-    Ok(ret)
 }
 
-fn setup_label_map<'a, T : Clone, Env>(func_def : &'a Vec<Instr<T, Env>>, current_function : Func) -> R<FuncDefWithLabel<'a, T, Env>> {
+/// Like `run`, but async sys calls are awaited instead of failing the run.  The future is not
+/// Send, so it needs an executor that can run local futures.
+pub async fn run_async<T : Clone, Env>( func_defs : &HashMap<Func, Vec<Instr<T, Env>>>, env: &mut Env ) -> R<Option<Data<T>>> {
+    run_compiled_async(&compile(func_defs)?, env).await
+}
 
-    let mut label_map : HashMap<Label, usize> = HashMap::new();
-    for (index, instr) in func_def.iter().enumerate() {
-        match instr {
-            Instr::Label(label) => {
-                match label_map.insert( *label, index ) {
-                    Some(_) => return Err(Box::new(VmError::RedefinitionOfLabel { label : label.0, func : current_function.0})),
-                    None => { },
-                }
+pub async fn run_compiled_async<T : Clone, Env>( program : &CompiledProgram<'_, T, Env>, env: &mut Env ) -> R<Option<Data<T>>> {
+    let mut vm = Vm::new(program)?;
+    loop {
+        match vm.run_to_completion(env).map_err(|error| runtime_error(program, &vm, error))? {
+            Status::Finished(ret) => return Ok(ret),
+            Status::Pending => {
+                let result = match vm.take_future() {
+                    Some(future) => match future.await {
+                        Ok(data) => vm.complete(data),
+                        Err(error) => vm.fail(error),
+                    },
+                    None => Err(VmError::PendingSysCall { func: vm.current_function().0 }.into()),
+                };
+                result.map_err(|error| runtime_error(program, &vm, error))?;
             },
-            _ => { },
+            // NOTE:  The vm is not metered here, so it can only stop by finishing or waiting.
+            _ => unreachable!(),
         }
     }
+}
 
-    Ok(FuncDefWithLabel { body: func_def, label_map })
+fn runtime_error<T : Clone, Env, Tr : Tracer<T, Env>>( program : &CompiledProgram<T, Env>, vm : &Vm<T, Env, Tr>, error : Box<dyn std::error::Error> ) -> Box<dyn std::error::Error> {
+    let message = match (error.downcast_ref::<VmError>(), program.debug_info()) {
+        (Some(vm_error), Some(debug_info)) => Some(vm_error.with_debug_info(debug_info).to_string()),
+        _ => None,
+    };
+    Box::new(RuntimeError { error, trace: vm.stack_trace(), message })
 }
 
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants, clippy::redundant_pattern_matching, clippy::needless_return)]
 mod tests {
     use super::*;
 
@@ -584,4 +525,381 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn should_reuse_compiled_program_across_runs() -> R<()> {
+        let init = Symbol(0);
+        let func_defs : HashMap<Func, Vec<Instr<usize, usize>>> = HashMap::from( 
+            [(Func(0), vec![ Instr::LoadFromSysCall(init, Box::new(
+                                move |_, env| {
+                                    *env += 1;
+                                    Ok(Data::Value(*env))
+                                }))
+                           , Instr::Return(init)
+                           ])
+            ]);
+
+        let program = compile(&func_defs)?;
+        let mut env : usize = 0;
+
+        for expected in 1..=3 {
+            if let Data::Value( result ) = run_compiled(&program, &mut env)?.unwrap() {
+                assert_eq!( result, expected );
+            }
+            else {
+                assert!(false);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_call_closure_with_captured_locals() -> R<()> {
+        let x = Symbol(0);
+        let y = Symbol(1);
+        let ret = Symbol(2);
+        let c = Symbol(3);
+        let func_defs : HashMap<Func, Vec<Instr<usize, usize>>> = HashMap::from( 
+            [(Func(0), vec![ Instr::LoadValue(x, 7)
+                           , Instr::MakeClosure(c, Func(1), vec![x])
+                           // NOTE:  The closure keeps the value x had when it was made.
+                           , Instr::LoadValue(x, 100)
+                           , Instr::LoadValue(y, 11)
+                           , Instr::PushParam(y)
+                           , Instr::Call(c)
+                           , Instr::LoadFromReturn(ret)
+                           , Instr::Return(ret)
+                           ])
+            ,(Func(1), vec![ Instr::PopParam(y)
+                           , Instr::LoadFromExec(ret, Box::new(
+                                move |locals| Ok(Data::Value(locals.value_ref(&x)? + locals.value_ref(&y)?))))
+                           , Instr::Return(ret)
+                           ])
+            ]);
+
+        if let Data::Value( result ) = run(&func_defs, &mut 0)?.unwrap() {
+            assert_eq!( result, 18 );
+        }
+        else {
+            assert!(false);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_call_partial_with_bound_args_first() -> R<()> {
+        let a = Symbol(0);
+        let b = Symbol(1);
+        let c = Symbol(2);
+        let f = Symbol(3);
+        let ret = Symbol(4);
+        let func_defs : HashMap<Func, Vec<Instr<usize, usize>>> = HashMap::from( 
+            [(Func(0), vec![ Instr::LoadValue(a, 1)
+                           , Instr::LoadValue(b, 2)
+                           , Instr::LoadValue(c, 3)
+                           , Instr::LoadFunc(f, Func(1))
+                           , Instr::Apply(f, f, vec![a])
+                           , Instr::Apply(f, f, vec![b])
+                           , Instr::PushParam(c)
+                           , Instr::Call(f)
+                           , Instr::LoadFromReturn(ret)
+                           , Instr::Return(ret)
+                           ])
+            ,(Func(1), vec![ Instr::PopParam(a)
+                           , Instr::PopParam(b)
+                           , Instr::PopParam(c)
+                           , Instr::LoadFromExec(ret, Box::new(
+                                move |locals| {
+                                    let digits = [a, b, c].iter().map(|s| locals.value_ref(s).copied()).collect::<R<Vec<_>>>()?;
+                                    Ok(Data::Value(digits.iter().fold(0, |acc, d| acc * 10 + d)))
+                                }))
+                           , Instr::Return(ret)
+                           ])
+            ]);
+
+        if let Data::Value( result ) = run(&func_defs, &mut 0)?.unwrap() {
+            assert_eq!( result, 123 );
+        }
+        else {
+            assert!(false);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_apply_closure_keeping_captures() -> R<()> {
+        let x = Symbol(0);
+        let y = Symbol(1);
+        let ret = Symbol(2);
+        let c = Symbol(3);
+        let func_defs : HashMap<Func, Vec<Instr<usize, usize>>> = HashMap::from( 
+            [(Func(0), vec![ Instr::LoadValue(x, 7)
+                           , Instr::MakeClosure(c, Func(1), vec![x])
+                           , Instr::LoadValue(y, 11)
+                           , Instr::Apply(c, c, vec![y])
+                           , Instr::LoadValue(x, 100)
+                           , Instr::Call(c)
+                           , Instr::LoadFromReturn(ret)
+                           , Instr::Return(ret)
+                           ])
+            ,(Func(1), vec![ Instr::PopParam(y)
+                           , Instr::LoadFromExec(ret, Box::new(
+                                move |locals| Ok(Data::Value(locals.value_ref(&x)? + locals.value_ref(&y)?))))
+                           , Instr::Return(ret)
+                           ])
+            ]);
+
+        assert!( matches!( run(&func_defs, &mut 0)?, Some(Data::Value(18)) ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_apply_native_function_value() -> R<()> {
+        let a = Symbol(0);
+        let b = Symbol(1);
+        let f = Symbol(2);
+        let ret = Symbol(3);
+        let func_defs : HashMap<Func, Vec<Instr<usize, usize>>> = HashMap::from( 
+            [(Func(0), vec![ Instr::LoadValue(a, 4)
+                           , Instr::LoadNative(f, Native(0))
+                           , Instr::Apply(f, f, vec![a])
+                           , Instr::LoadValue(b, 5)
+                           , Instr::PushParam(b)
+                           , Instr::Call(f)
+                           , Instr::LoadFromReturn(ret)
+                           , Instr::Return(ret)
+                           ])
+            ]);
+
+        let mut program = compile(&func_defs)?;
+        program.register_native(Native(0), |params, env| {
+            *env += 1;
+            match (params.pop(), params.pop()) {
+                (Some(Data::Value(a)), Some(Data::Value(b))) => Ok(Data::Value(a * 10 + b)),
+                _ => panic!("!"),
+            }
+        });
+
+        let mut env = 0;
+        assert!( matches!( run_compiled(&program, &mut env)?, Some(Data::Value(45)) ) );
+        assert_eq!( env, 1 );
+        Ok(())
+    }
+
+    #[test]
+    fn should_call_native_function_value() -> R<()> {
+        let a = Symbol(0);
+        let b = Symbol(1);
+        let add = Symbol(2);
+        let ret = Symbol(3);
+        let func_defs : HashMap<Func, Vec<Instr<usize, usize>>> = HashMap::from( 
+            [(Func(0), vec![ Instr::LoadValue(a, 4)
+                           , Instr::LoadValue(b, 5)
+                           , Instr::LoadNative(add, Native(0))
+                           , Instr::PushParam(add)
+                           , Instr::PushParam(b)
+                           , Instr::PushParam(a)
+                           , Instr::LoadFunc(add, Func(1))
+                           , Instr::Call(add)
+                           , Instr::LoadFromReturn(ret)
+                           , Instr::Return(ret)
+                           ])
+            // NOTE:  Calls whatever function it was given, be it native or not.
+            ,(Func(1), vec![ Instr::PopParam(a)
+                           , Instr::PopParam(b)
+                           , Instr::PopParam(add)
+                           , Instr::PushParam(b)
+                           , Instr::PushParam(a)
+                           , Instr::Call(add)
+                           , Instr::LoadFromReturn(ret)
+                           , Instr::Return(ret)
+                           ])
+            ]);
+
+        let mut program = compile(&func_defs)?;
+        program.register_native(Native(0), |params, env| {
+            *env += 1;
+            match (params.pop(), params.pop()) {
+                (Some(Data::Value(a)), Some(Data::Value(b))) => Ok(Data::Value(a + b)),
+                _ => panic!("!"),
+            }
+        });
+
+        let mut env = 0;
+        if let Data::Value( result ) = run_compiled(&program, &mut env)?.unwrap() {
+            assert_eq!( result, 9 );
+        }
+        else {
+            assert!(false);
+        }
+        assert_eq!( env, 1 );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_fail_to_call_unregistered_native() {
+        let f = Symbol(0);
+        let func_defs : HashMap<Func, Vec<Instr<usize, usize>>> = HashMap::from( 
+            [(Func(0), vec![ Instr::LoadNative(f, Native(3))
+                           , Instr::Call(f)
+                           ])
+            ]);
+
+        let error = run(&func_defs, &mut 0).unwrap_err();
+        let error = &error.downcast_ref::<RuntimeError>().unwrap().error;
+
+        assert!( matches!( error.downcast_ref::<VmError>(), Some(VmError::NativeDoesNotExist(3)) ) );
+    }
+
+    #[test]
+    fn should_wrap_closure_errors_with_stack_trace() -> R<()> {
+        let f = Symbol(0);
+        let func_defs : HashMap<Func, Vec<Instr<usize, usize>>> = HashMap::from( 
+            [(Func(0), vec![ Instr::LoadFunc(f, Func(1))
+                           , Instr::Label(Label(0))
+                           , Instr::Call(f)
+                           ])
+            ,(Func(1), vec![ Instr::LoadFunc(f, Func(2))
+                           , Instr::Call(f)
+                           ])
+            ,(Func(2), vec![ Instr::LoadValue(f, 0)
+                           , Instr::SysCall(Box::new(|_, _| Err("bad syscall".into())))
+                           ])
+            ]);
+
+        let error = run(&func_defs, &mut 0).unwrap_err();
+        let error = error.downcast_ref::<RuntimeError>().unwrap();
+
+        assert_eq!( error.error.to_string(), "bad syscall" );
+        assert_eq!( error.trace.iter().map(|frame| (frame.func, frame.instr_ptr)).collect::<Vec<_>>()
+                  , vec![(2, 1), (1, 1), (0, 2)]
+                  );
+        assert_eq!( error.to_string()
+                  , "bad syscall\n  at function 2 instruction 1\n  at function 1 instruction 1\n  at function 0 instruction 2"
+                  );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_describe_errors_with_debug_info() -> R<()> {
+        let input = "\
+func 0 main:
+    load_func s0 f1
+    call s0
+func 1 helper:
+    name s2 missing
+    return s2
+";
+        let assembly = asm::parse::<usize, usize>(input, &registry::Registry::new())?;
+        let mut program = compile(&assembly.func_defs)?;
+        program.set_debug_info(assembly.debug_info);
+
+        let error = run_compiled(&program, &mut 0).unwrap_err();
+        let error = error.downcast_ref::<RuntimeError>().unwrap();
+
+        assert_eq!( error.error.to_string(), "symbol 2 does not exist for function 1" );
+        assert_eq!( error.to_string()
+                  , "symbol missing does not exist for function helper\n  at helper (function 1) instruction 0 (6:5)\n  at main (function 0) instruction 1 (3:5)"
+                  );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_fail_to_run_pending_sys_call() {
+        let sym = Symbol(0);
+        let func_defs : HashMap<Func, Vec<Instr<usize, usize>>> = HashMap::from( 
+            [(Func(0), vec![ Instr::LoadFromPollSysCall(sym, Box::new(|_, _| Ok(std::task::Poll::Pending)))
+                           , Instr::Return(sym)
+                           ])
+            ]);
+
+        let error = run(&func_defs, &mut 0).unwrap_err();
+        let error = &error.downcast_ref::<RuntimeError>().unwrap().error;
+
+        assert!( matches!( error.downcast_ref::<VmError>(), Some(VmError::PendingSysCall { func: 0 }) ) );
+    }
+
+    fn block_on<F : std::future::Future>( future : F ) -> (F::Output, usize) {
+        let mut future = std::pin::pin!(future);
+        let mut context = std::task::Context::from_waker(std::task::Waker::noop());
+        let mut pending = 0;
+        loop {
+            match future.as_mut().poll(&mut context) {
+                std::task::Poll::Ready(output) => return (output, pending),
+                std::task::Poll::Pending => pending += 1,
+            }
+        }
+    }
+
+    struct YieldOnce(bool);
+
+    impl std::future::Future for YieldOnce {
+        type Output = ();
+        fn poll(mut self : std::pin::Pin<&mut Self>, context : &mut std::task::Context) -> std::task::Poll<()> {
+            if self.0 {
+                std::task::Poll::Ready(())
+            }
+            else {
+                self.0 = true;
+                context.waker().wake_by_ref();
+                std::task::Poll::Pending
+            }
+        }
+    }
+
+    #[test]
+    fn should_await_async_sys_calls() -> R<()> {
+        let x = Symbol(0);
+        let func_defs : HashMap<Func, Vec<Instr<usize, usize>>> = HashMap::from( 
+            [(Func(0), vec![ Instr::LoadValue(x, 20)
+                           , Instr::LoadFromAsyncSysCall(x, Box::new(move |locals, env| {
+                                 *env += 1;
+                                 let value = match locals.get(&x) {
+                                     Ok(Data::Value(value)) => value,
+                                     _ => 0,
+                                 };
+                                 Box::pin(async move {
+                                     YieldOnce(false).await;
+                                     Ok(Data::Value(value * 2))
+                                 })
+                             }))
+                           , Instr::LoadFromAsyncSysCall(x, Box::new(move |locals, env| {
+                                 *env += 1;
+                                 let value = locals.get(&x);
+                                 Box::pin(async move { Ok(Data::Value(match value? { Data::Value(value) => value + 2, _ => 0 })) })
+                             }))
+                           , Instr::Return(x)
+                           ])
+            ]);
+
+        let mut calls = 0;
+        let (result, pending) = block_on(run_async(&func_defs, &mut calls));
+
+        assert!( matches!( result?, Some(Data::Value(42)) ) );
+        assert_eq!( calls, 2 );
+        assert_eq!( pending, 1 );
+        Ok(())
+    }
+
+    #[test]
+    fn should_wrap_async_sys_call_errors_with_stack_trace() {
+        let x = Symbol(0);
+        let func_defs : HashMap<Func, Vec<Instr<usize, usize>>> = HashMap::from( 
+            [(Func(0), vec![ Instr::LoadFromAsyncSysCall(x, Box::new(|_, _| Box::pin(async { Err("no route".into()) })))
+                           , Instr::Return(x)
+                           ])
+            ]);
+
+        let (result, _) = block_on(run_async(&func_defs, &mut 0));
+        let error = result.unwrap_err();
+        let error = error.downcast_ref::<RuntimeError>().unwrap();
+
+        assert_eq!( error.to_string(), "no route\n  at function 0 instruction 0" );
+    }
 }
@@ -0,0 +1,304 @@
+
+//! A tracer which profiles a run per function.
+//!
+//! Time between two trace events is charged to the function on top of the stack, which makes it
+//! that function's exclusive time.  Inclusive time runs from a call to its return, and is only
+//! counted for the outermost activation of a recursive function.  Sys call time is measured
+//! around the host closure of each sys call instruction.
+//!
+//! A profiler can be used for many runs.  Functions a failed run left on the stack are charged up
+//! to its last event when the next run starts.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::data::*;
+use crate::debug::DebugInfo;
+use crate::trace::Tracer;
+
+#[derive(Debug, Clone, Default)]
+pub struct FuncProfile {
+    pub calls : u64,
+    pub instructions : u64,
+    pub inclusive : Duration,
+    pub exclusive : Duration,
+    pub sys_call : Duration,
+    /// Most activations of the function on the stack at once.
+    pub max_depth : usize,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct StackProfile {
+    instructions : u64,
+    exclusive : Duration,
+}
+
+// NOTE:  A call path, interned as a child of the path of its caller.
+#[derive(Debug)]
+struct Stack {
+    parent : usize,
+    func : Func,
+    profile : StackProfile,
+}
+
+// NOTE:  The empty path, on top of which the entry function runs.
+const ROOT : usize = 0;
+
+#[derive(Debug)]
+pub struct Profiler {
+    funcs : HashMap<Func, FuncProfile>,
+    stacks : Vec<Stack>,
+    children : HashMap<(usize, Func), usize>,
+    current : usize,
+    // NOTE:  Activations of each function on the stack, so that only the outermost one counts
+    // towards inclusive time.
+    live : HashMap<Func, usize>,
+    starts : Vec<Instant>,
+    last : Option<Instant>,
+    sys_call_start : Option<Instant>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        let root = Stack { parent: ROOT, func: Func(0), profile: StackProfile::default() };
+        Profiler { funcs: HashMap::new()
+                 , stacks: vec![root]
+                 , children: HashMap::new()
+                 , current: ROOT
+                 , live: HashMap::new()
+                 , starts: vec![]
+                 , last: None
+                 , sys_call_start: None
+                 }
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler::default()
+    }
+
+    pub fn func(&self, func : &Func) -> Option<&FuncProfile> {
+        self.funcs.get(func)
+    }
+
+    pub fn funcs(&self) -> impl Iterator<Item = (&Func, &FuncProfile)> {
+        self.funcs.iter()
+    }
+
+    /// Folded stacks weighted by instructions executed, one `f0;f1;f2 count` line per stack.
+    pub fn folded(&self, debug_info : Option<&DebugInfo>) -> String {
+        self.fold(debug_info, |stack| stack.instructions)
+    }
+
+    /// Folded stacks weighted by exclusive time in nanoseconds.
+    pub fn folded_time(&self, debug_info : Option<&DebugInfo>) -> String {
+        self.fold(debug_info, |stack| stack.exclusive.as_nanos() as u64)
+    }
+
+    fn fold( &self, debug_info : Option<&DebugInfo>, weight : impl Fn(&StackProfile) -> u64 ) -> String {
+        let name = |func : &Func| match debug_info.and_then(|info| info.func_name(func)) {
+            Some(name) => name.to_string(),
+            None => func.to_string(),
+        };
+
+        let path = |mut id : usize| {
+            let mut names = vec![];
+            while id != ROOT {
+                names.push(name(&self.stacks[id].func));
+                id = self.stacks[id].parent;
+            }
+            names.reverse();
+            names.join(";")
+        };
+
+        let mut lines = self.stacks.iter()
+                                   .enumerate()
+                                   .skip(1)
+                                   .map(|(id, stack)| (id, weight(&stack.profile)))
+                                   .filter(|(_, weight)| *weight > 0)
+                                   .map(|(id, weight)| (path(id), weight))
+                                   .collect::<Vec<_>>();
+        lines.sort();
+        lines.into_iter().map(|(path, weight)| format!("{} {}\n", path, weight)).collect()
+    }
+
+    // NOTE:  Charges the time since the last event to the function on top of the stack.
+    fn tick(&mut self) -> Instant {
+        let now = Instant::now();
+        if let Some(last) = self.last.filter(|_| self.current != ROOT) {
+            let elapsed = now - last;
+            let stack = &mut self.stacks[self.current];
+            stack.profile.exclusive += elapsed;
+            self.funcs.entry(stack.func).or_default().exclusive += elapsed;
+        }
+        self.last = Some(now);
+        now
+    }
+
+    fn enter(&mut self, func : Func, now : Instant) {
+        let next = self.stacks.len();
+        let parent = self.current;
+        self.current = *self.children.entry((parent, func)).or_insert(next);
+        if self.current == next {
+            self.stacks.push(Stack { parent, func, profile: StackProfile::default() });
+        }
+        self.starts.push(now);
+
+        let depth = self.live.entry(func).or_insert(0);
+        *depth += 1;
+        let profile = self.funcs.entry(func).or_default();
+        profile.calls += 1;
+        profile.max_depth = profile.max_depth.max(*depth);
+    }
+
+    fn leave(&mut self, now : Instant) {
+        if self.current == ROOT {
+            return;
+        }
+        if let Some(start) = self.starts.pop() {
+            let Stack { parent, func, .. } = self.stacks[self.current];
+            self.current = parent;
+            let depth = self.live.entry(func).or_insert(1);
+            *depth -= 1;
+            if *depth == 0 {
+                self.funcs.entry(func).or_default().inclusive += now - start;
+            }
+        }
+    }
+}
+
+impl<T : Clone, Env> Tracer<T, Env> for Profiler {
+    fn on_start(&mut self, _entry : Func) {
+        if let Some(last) = self.last {
+            while self.current != ROOT {
+                self.leave(last);
+            }
+        }
+        self.last = None;
+        self.sys_call_start = None;
+    }
+
+    fn on_instr(&mut self, func : Func, _ip : usize, _instr : &Instr<T, Env>) {
+        let now = self.tick();
+        // NOTE:  The entry function is running before any call is reported.
+        if self.current == ROOT {
+            self.enter(func, now);
+        }
+        self.funcs.entry(func).or_default().instructions += 1;
+        self.stacks[self.current].profile.instructions += 1;
+    }
+
+    fn on_call(&mut self, _from : Func, to : Func, _depth : usize) {
+        let now = self.tick();
+        self.enter(to, now);
+    }
+
+    fn on_return(&mut self, _func : Func, _ret : Option<&Data<T>>) {
+        let now = self.tick();
+        self.leave(now);
+    }
+
+    fn on_syscall(&mut self, _func : Func, _ip : usize) {
+        self.sys_call_start = Some(self.tick());
+    }
+
+    fn on_syscall_return(&mut self, func : Func, _ip : usize) {
+        if let Some(start) = self.sys_call_start.take() {
+            self.funcs.entry(func).or_default().sys_call += start.elapsed();
+        }
+    }
+
+    fn on_error(&mut self, _func : Func, _ip : usize, _error : &dyn std::error::Error) {
+        self.tick();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::R;
+
+    #[test]
+    fn should_count_calls_instructions_and_depth() -> R<()> {
+        let n = Symbol(0);
+        let f = Symbol(1);
+        let end = Label(0);
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadValue(n, 3)
+                           , Instr::PushParam(n)
+                           , Instr::LoadFunc(f, Func(1))
+                           , Instr::Call(f)
+                           , Instr::SysCall(Box::new(|_, _| { std::thread::sleep(Duration::from_millis(1)); Ok(()) }))
+                           ])
+            ,(Func(1), vec![ Instr::PopParam(n)
+                           , Instr::BranchOnTrue(end, Box::new(move |locals| Ok(matches!(locals.get(&n)?, Data::Value(0)))))
+                           , Instr::LoadFromExec(n, Box::new(move |locals| match locals.get(&n)? {
+                                 Data::Value(n) => Ok(Data::Value(n - 1)),
+                                 _ => Ok(Data::Value(0)),
+                             }))
+                           , Instr::PushParam(n)
+                           , Instr::LoadFunc(f, Func(1))
+                           , Instr::Call(f)
+                           , Instr::Label(end)
+                           ])
+            ]);
+
+        let mut profiler = Profiler::new();
+        crate::run_traced(&func_defs, &mut (), &mut profiler)?;
+
+        let main = profiler.func(&Func(0)).unwrap();
+        let recur = profiler.func(&Func(1)).unwrap();
+        assert_eq!( (main.calls, main.instructions, main.max_depth), (1, 5, 1) );
+        assert_eq!( (recur.calls, recur.instructions, recur.max_depth), (4, 3 * 6 + 2, 4) );
+        assert!( main.sys_call >= Duration::from_millis(1) );
+        assert!( main.inclusive >= main.exclusive + recur.inclusive );
+        assert!( recur.inclusive >= recur.exclusive );
+
+        assert_eq!( profiler.folded(None)
+                  , "f0 5\nf0;f1 6\nf0;f1;f1 6\nf0;f1;f1;f1 6\nf0;f1;f1;f1;f1 2\n"
+                  );
+        Ok(())
+    }
+
+    #[test]
+    fn should_start_over_when_reused_after_error() -> R<()> {
+        let func_defs : HashMap<Func, Vec<Instr<u8, u8>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadFunc(Symbol(0), Func(1))
+                           , Instr::Call(Symbol(0))
+                           ])
+            ,(Func(1), vec![ Instr::SysCall(Box::new(|_, env| if *env == 0 { Err("boom".into()) } else { Ok(()) })) ])
+            ]);
+
+        let mut profiler = Profiler::new();
+        assert!( crate::run_traced(&func_defs, &mut 0, &mut profiler).is_err() );
+        crate::run_traced(&func_defs, &mut 1, &mut profiler)?;
+
+        let main = profiler.func(&Func(0)).unwrap();
+        let callee = profiler.func(&Func(1)).unwrap();
+        assert_eq!( (main.calls, main.instructions), (2, 4) );
+        assert_eq!( (callee.calls, callee.instructions), (2, 2) );
+        assert!( main.inclusive >= main.exclusive + callee.inclusive );
+        assert_eq!( profiler.folded(None), "f0 4\nf0;f1 2\n" );
+        Ok(())
+    }
+
+    #[test]
+    fn should_name_folded_stacks_from_debug_info() -> R<()> {
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadFunc(Symbol(0), Func(1))
+                           , Instr::Call(Symbol(0))
+                           ])
+            ,(Func(1), vec![ Instr::LoadValue(Symbol(0), 1) ])
+            ]);
+
+        let mut profiler = Profiler::new();
+        crate::run_traced(&func_defs, &mut (), &mut profiler)?;
+
+        let mut debug_info = DebugInfo::new();
+        debug_info.func_names.insert(Func(0), "main".to_string());
+
+        assert_eq!( profiler.folded(Some(&debug_info)), "main 2\nmain;f1 1\n" );
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::task::Poll;
+
+use crate::data::*;
+use crate::R;
+
+type SharedBranchFn<T> = Rc<dyn Fn(&Locals<T>) -> R<bool>>;
+type SharedExecFn<T> = Rc<dyn Fn(&Locals<T>) -> R<Data<T>>>;
+type SharedSysCallFn<T, Env> = Rc<dyn Fn(&mut Locals<T>, &mut Env) -> R<()>>;
+type SharedLoadSysCallFn<T, Env> = Rc<dyn Fn(&mut Locals<T>, &mut Env) -> R<Data<T>>>;
+type SharedPollSysCallFn<T, Env> = Rc<dyn Fn(&mut Locals<T>, &mut Env) -> R<Poll<Data<T>>>>;
+type SharedAsyncSysCallFn<T, Env> = Rc<dyn Fn(&mut Locals<T>, &mut Env) -> SysCallFuture<T>>;
+
+/// Host supplied native functions, looked up by name when building closure bearing instructions
+/// from a textual or serialized program.  Each kind of closure has its own namespace.
+pub struct Registry<T : Clone, Env> {
+    branches : HashMap<String, SharedBranchFn<T>>,
+    execs : HashMap<String, SharedExecFn<T>>,
+    sys_calls : HashMap<String, SharedSysCallFn<T, Env>>,
+    load_sys_calls : HashMap<String, SharedLoadSysCallFn<T, Env>>,
+    poll_sys_calls : HashMap<String, SharedPollSysCallFn<T, Env>>,
+    async_sys_calls : HashMap<String, SharedAsyncSysCallFn<T, Env>>,
+}
+
+impl<T : Clone + 'static, Env : 'static> Registry<T, Env> {
+    pub fn new() -> Self {
+        Registry { branches: HashMap::new()
+                 , execs: HashMap::new()
+                 , sys_calls: HashMap::new()
+                 , load_sys_calls: HashMap::new()
+                 , poll_sys_calls: HashMap::new()
+                 , async_sys_calls: HashMap::new()
+                 }
+    }
+
+    pub fn register_branch(&mut self, name : &str, f : impl Fn(&Locals<T>) -> R<bool> + 'static) {
+        self.branches.insert(name.to_string(), Rc::new(f));
+    }
+
+    pub fn register_exec(&mut self, name : &str, f : impl Fn(&Locals<T>) -> R<Data<T>> + 'static) {
+        self.execs.insert(name.to_string(), Rc::new(f));
+    }
+
+    pub fn register_sys_call(&mut self, name : &str, f : impl Fn(&mut Locals<T>, &mut Env) -> R<()> + 'static) {
+        self.sys_calls.insert(name.to_string(), Rc::new(f));
+    }
+
+    pub fn register_load_sys_call(&mut self, name : &str, f : impl Fn(&mut Locals<T>, &mut Env) -> R<Data<T>> + 'static) {
+        self.load_sys_calls.insert(name.to_string(), Rc::new(f));
+    }
+
+    pub fn register_poll_sys_call(&mut self, name : &str, f : impl Fn(&mut Locals<T>, &mut Env) -> R<Poll<Data<T>>> + 'static) {
+        self.poll_sys_calls.insert(name.to_string(), Rc::new(f));
+    }
+
+    pub fn register_async_sys_call(&mut self, name : &str, f : impl Fn(&mut Locals<T>, &mut Env) -> SysCallFuture<T> + 'static) {
+        self.async_sys_calls.insert(name.to_string(), Rc::new(f));
+    }
+
+    pub fn branch(&self, name : &str) -> Option<BranchFn<T>> {
+        let f = self.branches.get(name)?.clone();
+        Some(Box::new(move |locals| f(locals)))
+    }
+
+    pub fn exec(&self, name : &str) -> Option<ExecFn<T>> {
+        let f = self.execs.get(name)?.clone();
+        Some(Box::new(move |locals| f(locals)))
+    }
+
+    pub fn sys_call(&self, name : &str) -> Option<SysCallFn<T, Env>> {
+        let f = self.sys_calls.get(name)?.clone();
+        Some(Box::new(move |locals, env| f(locals, env)))
+    }
+
+    pub fn load_sys_call(&self, name : &str) -> Option<LoadSysCallFn<T, Env>> {
+        let f = self.load_sys_calls.get(name)?.clone();
+        Some(Box::new(move |locals, env| f(locals, env)))
+    }
+
+    pub fn poll_sys_call(&self, name : &str) -> Option<PollSysCallFn<T, Env>> {
+        let f = self.poll_sys_calls.get(name)?.clone();
+        Some(Box::new(move |locals, env| f(locals, env)))
+    }
+
+    pub fn async_sys_call(&self, name : &str) -> Option<AsyncSysCallFn<T, Env>> {
+        let f = self.async_sys_calls.get(name)?.clone();
+        Some(Box::new(move |locals, env| f(locals, env)))
+    }
+}
+
+impl<T : Clone + 'static, Env : 'static> Default for Registry<T, Env> {
+    fn default() -> Self {
+        Registry::new()
+    }
+}
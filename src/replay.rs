@@ -0,0 +1,426 @@
+
+//! Logs of host results for deterministic replay.
+//!
+//! A recording vm logs the decision of every branch and the result of every sys call and native
+//! function.  A replaying vm takes those results from the log instead of calling the host, so a
+//! run can be reproduced without the environment it first ran against.  Exec closures only read
+//! locals and are always called.
+//!
+//! Sys calls are logged with the symbols their closures wrote and the data those symbols held
+//! afterwards, and natives with how many params they popped and the params they pushed.  Replay
+//! makes the same changes.  Async sys
+//! calls are logged as pending, followed by the output of their future or its failure.  Host
+//! errors are replayed as their messages.
+//!
+//! Layout:  the magic bytes `PRLG` followed by the same version and checksum header as a
+//! serialized program, then the number of events and each event.
+
+use crate::data::*;
+use crate::error::SerialError;
+use crate::serial::{seal, unseal, Serial};
+
+pub const LOG_MAGIC : &[u8; 4] = b"PRLG";
+
+/// Symbols a sys call closure wrote, with the data each held afterwards.  None when the closure
+/// took the data out.
+pub type Writes<T> = Vec<(Symbol, Option<Data<T>>)>;
+
+#[derive(Debug, Clone)]
+pub enum Event<T : Clone> {
+    Branch(bool),
+    /// A sys call which returns nothing completed.
+    SysCall(Writes<T>),
+    /// A sys call produced the value.
+    Value(Data<T>, Writes<T>),
+    /// A sys call answered pending.  The event which completes it follows.
+    Pending(Writes<T>),
+    /// A native function returned the value or failed with the message, after popping this many
+    /// params and then pushing these.
+    Native(Result<Data<T>, String>, usize, Vec<Data<T>>),
+    /// A host closure or the future of an async sys call failed with the message.
+    Error(String, Writes<T>),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SysCallLog<T : Clone> {
+    pub events : Vec<Event<T>>,
+}
+
+const BRANCH : u8 = 0;
+const SYS_CALL : u8 = 1;
+const VALUE : u8 = 2;
+const NATIVE : u8 = 3;
+const ERROR : u8 = 4;
+const PENDING : u8 = 5;
+
+const DATA_VALUE : u8 = 0;
+const DATA_FUNC : u8 = 1;
+const DATA_CLOSURE : u8 = 2;
+const DATA_PARTIAL : u8 = 3;
+const DATA_NATIVE : u8 = 4;
+
+impl<T : Clone> SysCallLog<T> {
+    pub fn new() -> Self {
+        SysCallLog { events: vec![] }
+    }
+}
+
+impl<T : Clone + Serial> SysCallLog<T> {
+    /// Coroutines hold a suspended frame and cannot be saved.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, SerialError> {
+        let mut payload = vec![];
+        self.events.len().encode(&mut payload);
+        for event in &self.events {
+            match event {
+                Event::Branch(taken) => { payload.push(BRANCH); taken.encode(&mut payload); },
+                Event::SysCall(writes) => { payload.push(SYS_CALL); encode_writes(writes, &mut payload)?; },
+                Event::Value(data, writes) => {
+                    payload.push(VALUE);
+                    encode_data(data, &mut payload)?;
+                    encode_writes(writes, &mut payload)?;
+                },
+                Event::Pending(writes) => { payload.push(PENDING); encode_writes(writes, &mut payload)?; },
+                Event::Native(result, popped, pushed) => {
+                    payload.push(NATIVE);
+                    match result {
+                        Ok(data) => { true.encode(&mut payload); encode_data(data, &mut payload)?; },
+                        Err(message) => { false.encode(&mut payload); message.encode(&mut payload); },
+                    }
+                    popped.encode(&mut payload);
+                    pushed.len().encode(&mut payload);
+                    for param in pushed {
+                        encode_data(param, &mut payload)?;
+                    }
+                },
+                Event::Error(message, writes) => {
+                    payload.push(ERROR);
+                    message.encode(&mut payload);
+                    encode_writes(writes, &mut payload)?;
+                },
+            }
+        }
+        Ok(seal(LOG_MAGIC, &payload))
+    }
+
+    pub fn from_bytes(bytes : &[u8]) -> Result<Self, SerialError> {
+        let mut input = unseal(bytes, LOG_MAGIC)?;
+
+        let count = usize::decode(&mut input)?;
+        let mut events = vec![];
+        for _ in 0..count {
+            let event = match u8::decode(&mut input)? {
+                BRANCH => Event::Branch(bool::decode(&mut input)?),
+                SYS_CALL => Event::SysCall(decode_writes(&mut input)?),
+                VALUE => {
+                    let data = decode_data(&mut input)?;
+                    Event::Value(data, decode_writes(&mut input)?)
+                },
+                PENDING => Event::Pending(decode_writes(&mut input)?),
+                NATIVE => {
+                    let result = if bool::decode(&mut input)? { Ok(decode_data(&mut input)?) } else { Err(String::decode(&mut input)?) };
+                    let popped = usize::decode(&mut input)?;
+                    let count = usize::decode(&mut input)?;
+                    let pushed = (0..count).map(|_| decode_data(&mut input)).collect::<Result<Vec<_>, _>>()?;
+                    Event::Native(result, popped, pushed)
+                },
+                ERROR => {
+                    let message = String::decode(&mut input)?;
+                    Event::Error(message, decode_writes(&mut input)?)
+                },
+                _ => return Err(SerialError::InvalidValue("event")),
+            };
+            events.push(event);
+        }
+
+        if !input.is_empty() {
+            return Err(SerialError::TrailingBytes(input.len()));
+        }
+        Ok(SysCallLog { events })
+    }
+}
+
+fn encode_writes<T : Clone + Serial>( writes : &Writes<T>, output : &mut Vec<u8> ) -> Result<(), SerialError> {
+    writes.len().encode(output);
+    for (sym, data) in writes {
+        sym.0.encode(output);
+        match data {
+            Some(data) => { true.encode(output); encode_data(data, output)?; },
+            None => false.encode(output),
+        }
+    }
+    Ok(())
+}
+
+fn decode_writes<T : Clone + Serial>( input : &mut &[u8] ) -> Result<Writes<T>, SerialError> {
+    let count = usize::decode(input)?;
+    let mut writes = vec![];
+    for _ in 0..count {
+        let sym = Symbol(usize::decode(input)?);
+        let data = if bool::decode(input)? { Some(decode_data(input)?) } else { None };
+        writes.push((sym, data));
+    }
+    Ok(writes)
+}
+
+fn encode_data<T : Clone + Serial>( data : &Data<T>, output : &mut Vec<u8> ) -> Result<(), SerialError> {
+    match data {
+        Data::Value(value) => { output.push(DATA_VALUE); value.encode(output); },
+        Data::Func(func) => { output.push(DATA_FUNC); func.0.encode(output); },
+        Data::Closure(func, captures) => {
+            output.push(DATA_CLOSURE);
+            func.0.encode(output);
+            captures.len().encode(output);
+            for (sym, capture) in captures.iter() {
+                sym.0.encode(output);
+                encode_data(capture, output)?;
+            }
+        },
+        Data::Partial(f, args) => {
+            output.push(DATA_PARTIAL);
+            encode_data(f, output)?;
+            args.len().encode(output);
+            for arg in args.iter() {
+                encode_data(arg, output)?;
+            }
+        },
+        Data::Native(native) => { output.push(DATA_NATIVE); native.0.encode(output); },
+        Data::Coroutine(_) => return Err(SerialError::InvalidValue("coroutine")),
+    }
+    Ok(())
+}
+
+fn decode_data<T : Clone + Serial>( input : &mut &[u8] ) -> Result<Data<T>, SerialError> {
+    let data = match u8::decode(input)? {
+        DATA_VALUE => Data::Value(T::decode(input)?),
+        DATA_FUNC => Data::Func(Func(usize::decode(input)?)),
+        DATA_CLOSURE => {
+            let func = Func(usize::decode(input)?);
+            let count = usize::decode(input)?;
+            let mut captures = vec![];
+            for _ in 0..count {
+                let sym = Symbol(usize::decode(input)?);
+                captures.push((sym, decode_data(input)?));
+            }
+            Data::Closure(func, std::rc::Rc::new(captures))
+        },
+        DATA_PARTIAL => {
+            let f = decode_data(input)?;
+            let count = usize::decode(input)?;
+            let args = (0..count).map(|_| decode_data(input)).collect::<Result<Vec<_>, _>>()?;
+            Data::Partial(std::rc::Rc::new(f), std::rc::Rc::new(args))
+        },
+        DATA_NATIVE => Data::Native(Native(usize::decode(input)?)),
+        _ => return Err(SerialError::InvalidValue("data")),
+    };
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use crate::compile::compile;
+    use crate::error::VmError;
+    use crate::vm::{Status, Vm};
+    use crate::R;
+
+    #[test]
+    fn should_replay_recorded_run_without_calling_host() -> R<()> {
+        let x = Symbol(0);
+        let y = Symbol(1);
+        let f = Symbol(2);
+        let func_defs : HashMap<Func, Vec<Instr<u8, u8>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadFromSysCall(x, Box::new(|_, env| Ok(Data::Value(*env))))
+                           , Instr::BranchOnTrue(Label(0), Box::new(move |locals| Ok(matches!(locals.get(&x)?, Data::Value(0)))))
+                           , Instr::SysCall(Box::new(move |locals, env| { *env += 1; locals.set(&y, Data::Value(*env)) }))
+                           , Instr::SysCall(Box::new(move |locals, _| { locals.take(&x)?; Ok(()) }))
+                           , Instr::PushParam(y)
+                           , Instr::LoadNative(f, Native(0))
+                           , Instr::Call(f)
+                           , Instr::LoadFromReturn(x)
+                           , Instr::PopParam(y)
+                           , Instr::LoadFromExec(x, Box::new(move |locals| match (locals.get(&x)?, locals.get(&y)?) {
+                                 (Data::Value(x), Data::Value(y)) => Ok(Data::Value(x + y)),
+                                 _ => Err("expected values".into()),
+                             }))
+                           , Instr::Label(Label(0))
+                           , Instr::Return(x)
+                           ])
+            ]);
+        let mut program = compile(&func_defs)?;
+        program.register_native(Native(0), |params, env| match params.pop() {
+            Some(Data::Value(x)) => {
+                params.push(Data::Value(*env));
+                Ok(Data::Value(x * 10 + *env))
+            },
+            _ => Err("expected a value".into()),
+        });
+
+        let mut log = SysCallLog::new();
+        let recorded = crate::run_compiled_recorded(&program, &mut 3, &mut log)?;
+        assert!( matches!( recorded, Some(Data::Value(48)) ) );
+        assert!( matches!( &log.events[..], [ Event::Value(Data::Value(3), no_writes)
+                                            , Event::Branch(false)
+                                            , Event::SysCall(set_y)
+                                            , Event::SysCall(took_x)
+                                            , Event::Native(Ok(Data::Value(44)), 1, pushed)
+                                            ] if no_writes.is_empty()
+                                              && matches!( set_y[..], [(Symbol(1), Some(Data::Value(4)))] )
+                                              && matches!( took_x[..], [(Symbol(0), None)] )
+                                              && matches!( pushed[..], [Data::Value(4)] ) ) );
+
+        let mut env = 0;
+        let replayed = crate::run_compiled_replayed(&program, &mut env, log)?;
+        assert!( matches!( replayed, Some(Data::Value(48)) ) );
+        assert_eq!( env, 0 );
+        Ok(())
+    }
+
+    #[test]
+    fn should_round_trip_log_through_bytes() -> R<()> {
+        let log : SysCallLog<u8> = SysCallLog { events: vec![ Event::Branch(true)
+                                                            , Event::SysCall(vec![(Symbol(4), Some(Data::Value(1))), (Symbol(5), None)])
+                                                            , Event::Value(Data::Closure(Func(2), Rc::new(vec![(Symbol(1), Data::Value(7))])), vec![])
+                                                            , Event::Pending(vec![(Symbol(0), Some(Data::Func(Func(3))))])
+                                                            , Event::Native(Ok(Data::Partial(Rc::new(Data::Func(Func(1))), Rc::new(vec![Data::Native(Native(3))]))), 1, vec![Data::Value(2)])
+                                                            , Event::Native(Err("no such file".to_string()), 2, vec![])
+                                                            , Event::Error("disk on fire".to_string(), vec![(Symbol(2), None)])
+                                                            ] };
+
+        let bytes = log.to_bytes()?;
+        let decoded = SysCallLog::<u8>::from_bytes(&bytes)?;
+
+        assert_eq!( format!("{:?}", decoded), format!("{:?}", log) );
+        assert!( SysCallLog::<u8>::from_bytes(&bytes[..bytes.len() - 1]).is_err() );
+        Ok(())
+    }
+
+    #[test]
+    fn should_replay_recorded_errors_and_fail_on_divergence() -> R<()> {
+        let func_defs : HashMap<Func, Vec<Instr<u8, u8>>> = HashMap::from(
+            [(Func(0), vec![ Instr::SysCall(Box::new(|_, _| Err("disk on fire".into()))) ])
+            ]);
+
+        let mut log = SysCallLog::new();
+        assert!( crate::run_recorded(&func_defs, &mut 0, &mut log).is_err() );
+        assert!( matches!( &log.events[..], [Event::Error(message, writes)] if message == "disk on fire" && writes.is_empty() ) );
+
+        let error = crate::run_replayed(&func_defs, &mut 0, log).unwrap_err();
+        assert!( error.to_string().contains("disk on fire") );
+
+        let log = SysCallLog { events: vec![ Event::Branch(true) ] };
+        let error = crate::run_replayed(&func_defs, &mut 0, log).unwrap_err();
+        let error = error.downcast_ref::<crate::error::RuntimeError>().unwrap();
+        assert!( matches!( error.error.downcast_ref::<VmError>(), Some(VmError::ReplayDiverged { func: 0 }) ) );
+
+        let func_defs : HashMap<Func, Vec<Instr<u8, u8>>> = HashMap::from(
+            [(Func(0), vec![ Instr::SysCall(Box::new(|_, _| Ok(()))) ])
+            ]);
+        let log = SysCallLog { events: vec![ Event::SysCall(vec![]), Event::Branch(true) ] };
+        let error = crate::run_replayed(&func_defs, &mut 0, log).unwrap_err();
+        let error = error.downcast_ref::<crate::error::RuntimeError>().unwrap();
+        assert!( matches!( error.error.downcast_ref::<VmError>(), Some(VmError::ReplayDiverged { func: 0 }) ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_replay_params_popped_by_failed_native() -> R<()> {
+        let x = Symbol(0);
+        let f = Symbol(1);
+        let func_defs : HashMap<Func, Vec<Instr<u8, u8>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadValue(x, 1)
+                           , Instr::PushParam(x)
+                           , Instr::LoadValue(x, 2)
+                           , Instr::PushParam(x)
+                           , Instr::Try(Label(0))
+                           , Instr::LoadNative(f, Native(0))
+                           , Instr::Call(f)
+                           , Instr::EndTry
+                           , Instr::Label(Label(0))
+                           , Instr::PopParam(x)
+                           , Instr::Return(x)
+                           ])
+            ]);
+        let mut program = compile(&func_defs)?;
+        program.register_native(Native(0), |params, _| {
+            params.pop();
+            Err("bad argument".into())
+        });
+
+        let mut vm = Vm::new(&program)?;
+        vm.catch_errors(|_| Data::Value(0));
+        vm.start_recording();
+        assert!( matches!( vm.run_to_completion(&mut 0)?, Status::Finished(Some(Data::Value(1))) ) );
+
+        let log = vm.take_recording().unwrap();
+        assert!( matches!( &log.events[..], [Event::Native(Err(message), 1, pushed)] if message == "bad argument" && pushed.is_empty() ) );
+
+        let mut vm = Vm::new(&program)?;
+        vm.catch_errors(|_| Data::Value(0));
+        vm.replay(log);
+        assert!( matches!( vm.run_to_completion(&mut 0)?, Status::Finished(Some(Data::Value(1))) ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_replay_async_sys_call_results_and_failures() -> R<()> {
+        let x = Symbol(0);
+        let y = Symbol(1);
+        let func_defs : HashMap<Func, Vec<Instr<u8, u8>>> = HashMap::from(
+            [(Func(0), vec![ Instr::Try(Label(0))
+                           , Instr::LoadFromAsyncSysCall(x, Box::new(move |locals, env| {
+                                 let value = *env;
+                                 let _ = locals.set(&y, Data::Value(value));
+                                 Box::pin(async move { Ok(Data::Value(value + 1)) })
+                             }))
+                           , Instr::LoadFromAsyncSysCall(x, Box::new(|_, env| {
+                                 let fail = *env == 1;
+                                 Box::pin(async move { if fail { Err("offline".into()) } else { Ok(Data::Value(0)) } })
+                             }))
+                           , Instr::EndTry
+                           , Instr::Return(x)
+                           , Instr::Label(Label(0))
+                           , Instr::Catch(x)
+                           , Instr::LoadFromExec(x, Box::new(move |locals| match (locals.get(&x)?, locals.get(&y)?) {
+                                 (Data::Value(x), Data::Value(y)) => Ok(Data::Value(x * 10 + y)),
+                                 _ => Err("expected values".into()),
+                             }))
+                           , Instr::Return(x)
+                           ])
+            ]);
+        let program = compile(&func_defs)?;
+
+        let mut context = std::task::Context::from_waker(std::task::Waker::noop());
+        let mut vm = Vm::new(&program)?;
+        vm.catch_errors(|error| Data::Value(error.to_string().len() as u8));
+        vm.start_recording();
+        let mut env = 1;
+        let status = loop {
+            match vm.run_to_completion(&mut env)? {
+                Status::Pending => match vm.take_future().unwrap().as_mut().poll(&mut context) {
+                    std::task::Poll::Ready(Ok(data)) => vm.complete(data)?,
+                    std::task::Poll::Ready(Err(error)) => vm.fail(error)?,
+                    std::task::Poll::Pending => panic!("the futures are always ready"),
+                },
+                status => break status,
+            }
+        };
+        assert!( matches!( status, Status::Finished(Some(Data::Value(71))) ) );
+
+        let log = vm.take_recording().unwrap();
+        assert!( matches!( &log.events[..], [ Event::Pending(set_y)
+                                            , Event::Value(Data::Value(2), _)
+                                            , Event::Pending(_)
+                                            , Event::Error(message, _)
+                                            ] if matches!( set_y[..], [(Symbol(1), Some(Data::Value(1)))] )
+                                              && message == "offline" ) );
+
+        let mut vm = Vm::new(&program)?;
+        vm.catch_errors(|error| Data::Value(error.to_string().len() as u8));
+        vm.replay(log);
+        let mut env = 0;
+        assert!( matches!( vm.run_to_completion(&mut env)?, Status::Finished(Some(Data::Value(71))) ) );
+        Ok(())
+    }
+}
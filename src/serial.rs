@@ -0,0 +1,521 @@
+
+//! Versioned binary format for programs.
+//!
+//! Layout:  the magic bytes `PRPL`, a little endian u16 format version, a u32 FNV-1a checksum of
+//! the payload, and then the payload.  The payload starts with the table of native function names
+//! followed by every function and its instructions.  Closure bearing instructions store an index
+//! into the name table and are rebound against a `Registry` when loaded.  Debug info is not
+//! saved.
+
+use std::collections::HashMap;
+
+use crate::asm::Assembly;
+use crate::data::*;
+use crate::debug::DebugInfo;
+use crate::error::SerialError;
+use crate::registry::Registry;
+
+pub const MAGIC : &[u8; 4] = b"PRPL";
+pub const VERSION : u16 = 1;
+
+/// Values which can be written into and read back out of the binary format.
+pub trait Serial : Sized {
+    fn encode(&self, output : &mut Vec<u8>);
+    fn decode(input : &mut &[u8]) -> Result<Self, SerialError>;
+}
+
+fn take<'a>(input : &mut &'a [u8], count : usize) -> Result<&'a [u8], SerialError> {
+    if input.len() < count {
+        return Err(SerialError::UnexpectedEnd);
+    }
+    let (bytes, rest) = input.split_at(count);
+    *input = rest;
+    Ok(bytes)
+}
+
+macro_rules! serial_number {
+    ($($t:ty),*) => {
+        $(impl Serial for $t {
+            fn encode(&self, output : &mut Vec<u8>) {
+                output.extend_from_slice(&self.to_le_bytes());
+            }
+
+            fn decode(input : &mut &[u8]) -> Result<Self, SerialError> {
+                let bytes = take(input, std::mem::size_of::<$t>())?;
+                // NOTE:  take returns exactly the requested number of bytes.
+                Ok(<$t>::from_le_bytes(bytes.try_into().unwrap()))
+            }
+        })*
+    };
+}
+
+serial_number!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+impl Serial for usize {
+    fn encode(&self, output : &mut Vec<u8>) {
+        (*self as u64).encode(output);
+    }
+
+    fn decode(input : &mut &[u8]) -> Result<Self, SerialError> {
+        usize::try_from(u64::decode(input)?).map_err(|_| SerialError::InvalidValue("usize"))
+    }
+}
+
+impl Serial for isize {
+    fn encode(&self, output : &mut Vec<u8>) {
+        (*self as i64).encode(output);
+    }
+
+    fn decode(input : &mut &[u8]) -> Result<Self, SerialError> {
+        isize::try_from(i64::decode(input)?).map_err(|_| SerialError::InvalidValue("isize"))
+    }
+}
+
+impl Serial for bool {
+    fn encode(&self, output : &mut Vec<u8>) {
+        (*self as u8).encode(output);
+    }
+
+    fn decode(input : &mut &[u8]) -> Result<Self, SerialError> {
+        match u8::decode(input)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SerialError::InvalidValue("bool")),
+        }
+    }
+}
+
+impl Serial for String {
+    fn encode(&self, output : &mut Vec<u8>) {
+        self.len().encode(output);
+        output.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode(input : &mut &[u8]) -> Result<Self, SerialError> {
+        let len = usize::decode(input)?;
+        let bytes = take(input, len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| SerialError::InvalidValue("string"))
+    }
+}
+
+/// Puts the magic bytes, format version and payload checksum in front of the payload.
+pub(crate) fn seal(magic : &[u8; 4], payload : &[u8]) -> Vec<u8> {
+    let mut output = magic.to_vec();
+    VERSION.encode(&mut output);
+    checksum(payload).encode(&mut output);
+    output.extend_from_slice(payload);
+    output
+}
+
+/// Checks the header written by seal and returns the payload.
+pub(crate) fn unseal<'a>(bytes : &'a [u8], magic : &[u8; 4]) -> Result<&'a [u8], SerialError> {
+    let mut input = bytes;
+
+    if take(&mut input, magic.len()).ok() != Some(&magic[..]) {
+        return Err(SerialError::BadMagic);
+    }
+
+    let version = u16::decode(&mut input)?;
+    if version != VERSION {
+        return Err(SerialError::UnsupportedVersion { found: version, expected: VERSION });
+    }
+
+    let expected = u32::decode(&mut input)?;
+    let found = checksum(input);
+    if found != expected {
+        return Err(SerialError::ChecksumMismatch { found, expected });
+    }
+
+    Ok(input)
+}
+
+/// 32 bit FNV-1a.
+pub fn checksum(bytes : &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x01000193))
+}
+
+const LABEL : u8 = 0;
+const JUMP : u8 = 1;
+const BRANCH_ON_TRUE : u8 = 2;
+const RETURN : u8 = 3;
+const LOAD_VALUE : u8 = 4;
+const LOAD_FROM_RETURN : u8 = 5;
+const PUSH_PARAM : u8 = 6;
+const POP_PARAM : u8 = 7;
+const LOAD_FROM_EXEC : u8 = 8;
+const LOAD_FUNC : u8 = 9;
+const CALL : u8 = 10;
+const SYS_CALL : u8 = 11;
+const LOAD_FROM_SYS_CALL : u8 = 12;
+const MAKE_CLOSURE : u8 = 13;
+const APPLY : u8 = 14;
+const LOAD_NATIVE : u8 = 15;
+const TRY : u8 = 16;
+const END_TRY : u8 = 17;
+const THROW : u8 = 18;
+const CATCH : u8 = 19;
+const TAIL_CALL : u8 = 20;
+const YIELD : u8 = 21;
+const RESUME : u8 = 22;
+const LOAD_FROM_POLL_SYS_CALL : u8 = 23;
+const LOAD_FROM_ASYNC_SYS_CALL : u8 = 24;
+
+pub fn to_bytes<T : Clone + Serial, Env>( assembly : &Assembly<T, Env> ) -> Result<Vec<u8>, SerialError> {
+
+    let mut funcs = assembly.func_defs.keys().copied().collect::<Vec<_>>();
+    funcs.sort_by_key(|func| func.0);
+
+    let mut natives : Vec<&str> = vec![];
+    let mut native_indices : HashMap<&str, usize> = HashMap::new();
+    let mut body = vec![];
+
+    funcs.len().encode(&mut body);
+    for func in funcs {
+        let instrs = &assembly.func_defs[&func];
+        func.0.encode(&mut body);
+        instrs.len().encode(&mut body);
+
+        for (index, instr) in instrs.iter().enumerate() {
+            let mut native = || -> Result<usize, SerialError> {
+                let name = assembly.native_names.get(&(func, index))
+                                                .ok_or(SerialError::MissingNativeName { func: func.0, index })?;
+                Ok(*native_indices.entry(name.as_str()).or_insert_with(|| {
+                    natives.push(name.as_str());
+                    natives.len() - 1
+                }))
+            };
+
+            match instr {
+                Instr::Label(label) => { body.push(LABEL); label.0.encode(&mut body); },
+                Instr::Jump(label) => { body.push(JUMP); label.0.encode(&mut body); },
+                Instr::BranchOnTrue(label, _) => {
+                    body.push(BRANCH_ON_TRUE);
+                    label.0.encode(&mut body);
+                    native()?.encode(&mut body);
+                },
+                Instr::Return(sym) => { body.push(RETURN); sym.0.encode(&mut body); },
+                Instr::LoadValue(sym, value) => {
+                    body.push(LOAD_VALUE);
+                    sym.0.encode(&mut body);
+                    value.encode(&mut body);
+                },
+                Instr::LoadFromReturn(sym) => { body.push(LOAD_FROM_RETURN); sym.0.encode(&mut body); },
+                Instr::PushParam(sym) => { body.push(PUSH_PARAM); sym.0.encode(&mut body); },
+                Instr::PopParam(sym) => { body.push(POP_PARAM); sym.0.encode(&mut body); },
+                Instr::LoadFromExec(sym, _) => {
+                    body.push(LOAD_FROM_EXEC);
+                    sym.0.encode(&mut body);
+                    native()?.encode(&mut body);
+                },
+                Instr::LoadFunc(sym, f) => {
+                    body.push(LOAD_FUNC);
+                    sym.0.encode(&mut body);
+                    f.0.encode(&mut body);
+                },
+                Instr::LoadNative(sym, n) => {
+                    body.push(LOAD_NATIVE);
+                    sym.0.encode(&mut body);
+                    n.0.encode(&mut body);
+                },
+                Instr::Call(sym) => { body.push(CALL); sym.0.encode(&mut body); },
+                Instr::TailCall(sym) => { body.push(TAIL_CALL); sym.0.encode(&mut body); },
+                Instr::Yield(sym) => { body.push(YIELD); sym.0.encode(&mut body); },
+                Instr::Resume(sym) => { body.push(RESUME); sym.0.encode(&mut body); },
+                Instr::MakeClosure(sym, f, captures) => {
+                    body.push(MAKE_CLOSURE);
+                    sym.0.encode(&mut body);
+                    f.0.encode(&mut body);
+                    captures.len().encode(&mut body);
+                    for capture in captures {
+                        capture.0.encode(&mut body);
+                    }
+                },
+                Instr::Apply(sym, f, args) => {
+                    body.push(APPLY);
+                    sym.0.encode(&mut body);
+                    f.0.encode(&mut body);
+                    args.len().encode(&mut body);
+                    for arg in args {
+                        arg.0.encode(&mut body);
+                    }
+                },
+                Instr::Try(label) => { body.push(TRY); label.0.encode(&mut body); },
+                Instr::EndTry => { body.push(END_TRY); },
+                Instr::Throw(sym) => { body.push(THROW); sym.0.encode(&mut body); },
+                Instr::Catch(sym) => { body.push(CATCH); sym.0.encode(&mut body); },
+                Instr::SysCall(_) => {
+                    body.push(SYS_CALL);
+                    native()?.encode(&mut body);
+                },
+                Instr::LoadFromSysCall(sym, _) => {
+                    body.push(LOAD_FROM_SYS_CALL);
+                    sym.0.encode(&mut body);
+                    native()?.encode(&mut body);
+                },
+                Instr::LoadFromPollSysCall(sym, _) => {
+                    body.push(LOAD_FROM_POLL_SYS_CALL);
+                    sym.0.encode(&mut body);
+                    native()?.encode(&mut body);
+                },
+                Instr::LoadFromAsyncSysCall(sym, _) => {
+                    body.push(LOAD_FROM_ASYNC_SYS_CALL);
+                    sym.0.encode(&mut body);
+                    native()?.encode(&mut body);
+                },
+            }
+        }
+    }
+
+    let mut payload = vec![];
+    natives.len().encode(&mut payload);
+    for name in natives {
+        name.to_string().encode(&mut payload);
+    }
+    payload.extend_from_slice(&body);
+
+    Ok(seal(MAGIC, &payload))
+}
+
+pub fn from_bytes<T, Env>( bytes : &[u8], registry : &Registry<T, Env> ) -> Result<Assembly<T, Env>, SerialError>
+    where T : Clone + Serial + 'static, Env : 'static {
+
+    let mut input = unseal(bytes, MAGIC)?;
+
+    let native_count = usize::decode(&mut input)?;
+    let natives = (0..native_count).map(|_| String::decode(&mut input)).collect::<Result<Vec<_>, _>>()?;
+
+    let mut func_defs = HashMap::new();
+    let mut native_names = HashMap::new();
+
+    let func_count = usize::decode(&mut input)?;
+    for _ in 0..func_count {
+        let func = Func(usize::decode(&mut input)?);
+        if func_defs.contains_key(&func) {
+            return Err(SerialError::DuplicateFunction(func.0));
+        }
+        let instr_count = usize::decode(&mut input)?;
+        let mut instrs = vec![];
+
+        for index in 0..instr_count {
+            let mut native = |input : &mut &[u8]| -> Result<&str, SerialError> {
+                let native_index = usize::decode(input)?;
+                let name = natives.get(native_index).ok_or(SerialError::InvalidNativeIndex(native_index))?;
+                native_names.insert((func, index), name.clone());
+                Ok(name.as_str())
+            };
+
+            let unknown = |name : &str| SerialError::UnknownNative(name.to_string());
+
+            let instr = match u8::decode(&mut input)? {
+                LABEL => Instr::Label(Label(usize::decode(&mut input)?)),
+                JUMP => Instr::Jump(Label(usize::decode(&mut input)?)),
+                BRANCH_ON_TRUE => {
+                    let label = Label(usize::decode(&mut input)?);
+                    let name = native(&mut input)?;
+                    Instr::BranchOnTrue(label, registry.branch(name).ok_or_else(|| unknown(name))?)
+                },
+                RETURN => Instr::Return(Symbol(usize::decode(&mut input)?)),
+                LOAD_VALUE => {
+                    let sym = Symbol(usize::decode(&mut input)?);
+                    Instr::LoadValue(sym, T::decode(&mut input)?)
+                },
+                LOAD_FROM_RETURN => Instr::LoadFromReturn(Symbol(usize::decode(&mut input)?)),
+                PUSH_PARAM => Instr::PushParam(Symbol(usize::decode(&mut input)?)),
+                POP_PARAM => Instr::PopParam(Symbol(usize::decode(&mut input)?)),
+                LOAD_FROM_EXEC => {
+                    let sym = Symbol(usize::decode(&mut input)?);
+                    let name = native(&mut input)?;
+                    Instr::LoadFromExec(sym, registry.exec(name).ok_or_else(|| unknown(name))?)
+                },
+                LOAD_FUNC => {
+                    let sym = Symbol(usize::decode(&mut input)?);
+                    Instr::LoadFunc(sym, Func(usize::decode(&mut input)?))
+                },
+                LOAD_NATIVE => {
+                    let sym = Symbol(usize::decode(&mut input)?);
+                    Instr::LoadNative(sym, Native(usize::decode(&mut input)?))
+                },
+                CALL => Instr::Call(Symbol(usize::decode(&mut input)?)),
+                TAIL_CALL => Instr::TailCall(Symbol(usize::decode(&mut input)?)),
+                YIELD => Instr::Yield(Symbol(usize::decode(&mut input)?)),
+                RESUME => Instr::Resume(Symbol(usize::decode(&mut input)?)),
+                MAKE_CLOSURE => {
+                    let sym = Symbol(usize::decode(&mut input)?);
+                    let f = Func(usize::decode(&mut input)?);
+                    let count = usize::decode(&mut input)?;
+                    let captures = (0..count).map(|_| Ok(Symbol(usize::decode(&mut input)?))).collect::<Result<Vec<_>, SerialError>>()?;
+                    Instr::MakeClosure(sym, f, captures)
+                },
+                APPLY => {
+                    let sym = Symbol(usize::decode(&mut input)?);
+                    let f = Symbol(usize::decode(&mut input)?);
+                    let count = usize::decode(&mut input)?;
+                    let args = (0..count).map(|_| Ok(Symbol(usize::decode(&mut input)?))).collect::<Result<Vec<_>, SerialError>>()?;
+                    Instr::Apply(sym, f, args)
+                },
+                TRY => Instr::Try(Label(usize::decode(&mut input)?)),
+                END_TRY => Instr::EndTry,
+                THROW => Instr::Throw(Symbol(usize::decode(&mut input)?)),
+                CATCH => Instr::Catch(Symbol(usize::decode(&mut input)?)),
+                SYS_CALL => {
+                    let name = native(&mut input)?;
+                    Instr::SysCall(registry.sys_call(name).ok_or_else(|| unknown(name))?)
+                },
+                LOAD_FROM_SYS_CALL => {
+                    let sym = Symbol(usize::decode(&mut input)?);
+                    let name = native(&mut input)?;
+                    Instr::LoadFromSysCall(sym, registry.load_sys_call(name).ok_or_else(|| unknown(name))?)
+                },
+                LOAD_FROM_POLL_SYS_CALL => {
+                    let sym = Symbol(usize::decode(&mut input)?);
+                    let name = native(&mut input)?;
+                    Instr::LoadFromPollSysCall(sym, registry.poll_sys_call(name).ok_or_else(|| unknown(name))?)
+                },
+                LOAD_FROM_ASYNC_SYS_CALL => {
+                    let sym = Symbol(usize::decode(&mut input)?);
+                    let name = native(&mut input)?;
+                    Instr::LoadFromAsyncSysCall(sym, registry.async_sys_call(name).ok_or_else(|| unknown(name))?)
+                },
+                opcode => return Err(SerialError::InvalidOpcode(opcode)),
+            };
+
+            instrs.push(instr);
+        }
+
+        func_defs.insert(func, instrs);
+    }
+
+    if !input.is_empty() {
+        return Err(SerialError::TrailingBytes(input.len()));
+    }
+
+    Ok(Assembly { func_defs, native_names, debug_info: DebugInfo::new() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::parse;
+    use crate::disasm::disassemble_assembly;
+    use crate::R;
+
+    fn registry() -> Registry<i64, i64> {
+        let mut registry = Registry::new();
+        registry.register_branch("never", |_| Ok(false));
+        registry.register_exec("one", |_| Ok(Data::Value(1)));
+        registry.register_sys_call("bump", |_, env| { *env += 1; Ok(()) });
+        registry.register_load_sys_call("env", |_, env| Ok(Data::Value(*env)));
+        registry.register_poll_sys_call("ready", |_, env| Ok(std::task::Poll::Ready(Data::Value(*env))));
+        registry.register_async_sys_call("later", |_, _| Box::pin(async { Ok(Data::Value(0)) }));
+        registry
+    }
+
+    const PROGRAM : &str = "\
+func 0:
+    load_value s0 -5
+    push_param s0
+    load_func s1 f1
+    apply s2 s1
+    load_native s3 n0
+    make_closure s1 f1 s0
+    call s1
+    load_from_return s0
+    return s0
+func 1:
+    pop_param s0
+    label l0
+    branch_on_true l0 never
+    load_from_exec s1 one
+    sys_call bump
+    sys_call bump
+    load_from_poll_sys_call s2 ready
+    load_from_sys_call s2 env
+    jump l1
+    label l1
+    try l2
+    throw s2
+    end_try
+    label l2
+    catch s2
+    return s2
+    tail_call s1
+    yield s2
+    resume s1
+    load_from_async_sys_call s2 later
+";
+
+    #[test]
+    fn should_round_trip_program() -> R<()> {
+        let registry = registry();
+        let assembly = parse(PROGRAM, &registry)?;
+
+        let bytes = to_bytes(&assembly)?;
+        let loaded = from_bytes(&bytes, &registry)?;
+
+        assert_eq!( disassemble_assembly(&loaded), PROGRAM );
+
+        let mut env = 10;
+        assert!( matches!( crate::run(&loaded.func_defs, &mut env)?, Some(Data::Value(12)) ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_corrupted_payload() -> R<()> {
+        let registry = registry();
+        let mut bytes = to_bytes(&parse(PROGRAM, &registry)?)?;
+
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+
+        assert!( matches!( from_bytes(&bytes, &registry), Err(SerialError::ChecksumMismatch { .. }) ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_other_versions() -> R<()> {
+        let registry = registry();
+        let mut bytes = to_bytes(&parse(PROGRAM, &registry)?)?;
+
+        bytes[4] = 9;
+
+        assert!( matches!( from_bytes(&bytes, &registry), Err(SerialError::UnsupportedVersion { found: 9, expected: VERSION }) ) );
+        assert!( matches!( from_bytes(b"nope", &registry), Err(SerialError::BadMagic) ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_require_natives_at_load_time() -> R<()> {
+        let bytes = to_bytes(&parse(PROGRAM, &registry())?)?;
+
+        let mut partial : Registry<i64, i64> = Registry::new();
+        partial.register_branch("never", |_| Ok(false));
+
+        assert!( matches!( from_bytes(&bytes, &partial), Err(SerialError::UnknownNative(name)) if name == "one" ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_duplicate_functions() {
+        let mut payload = vec![];
+        0usize.encode(&mut payload);
+        2usize.encode(&mut payload);
+        for _ in 0..2 {
+            3usize.encode(&mut payload);
+            0usize.encode(&mut payload);
+        }
+
+        let result = from_bytes(&seal(MAGIC, &payload), &registry());
+
+        assert!( matches!( result, Err(SerialError::DuplicateFunction(3)) ) );
+    }
+
+    #[test]
+    fn should_require_native_names_when_saving() {
+        let assembly : Assembly<i64, i64> = Assembly { func_defs: HashMap::from([(Func(0), vec![Instr::SysCall(Box::new(|_, _| Ok(())))])])
+                                                     , native_names: HashMap::new()
+                                                     , debug_info: DebugInfo::new()
+                                                     };
+
+        assert!( matches!( to_bytes(&assembly), Err(SerialError::MissingNativeName { func: 0, index: 0 }) ) );
+    }
+}
@@ -0,0 +1,163 @@
+
+//! Hooks for observing a running vm.  Every callback has an empty default, and the vm uses
+//! `NoTracer` unless another tracer is installed, so tracing costs nothing when unused.
+//!
+//! Instruction pointers are indices into the function's source instructions, as reported by
+//! `Vm::instr_ptr`.
+
+use crate::data::*;
+
+pub trait Tracer<T : Clone, Env> {
+    /// Called when a vm is created with the tracer, before its entry function runs.
+    fn on_start(&mut self, _entry : Func) { }
+
+    /// Called before each instruction executes.
+    fn on_instr(&mut self, _func : Func, _ip : usize, _instr : &Instr<T, Env>) { }
+
+    /// Called after a branch decides whether to jump.
+    fn on_branch(&mut self, _func : Func, _ip : usize, _taken : bool) { }
+
+    /// Called when a function is entered, after its frame is pushed.  Depth is the number of
+    /// suspended frames below it.  A tail call keeps the depth of the function it replaces.
+    fn on_call(&mut self, _from : Func, _to : Func, _depth : usize) { }
+
+    /// Called when a function's frame is removed.  Ret is the value returned or yielded, and is
+    /// None when the function ran off its end, was replaced by a tail call or was unwound by an
+    /// exception.
+    fn on_return(&mut self, _func : Func, _ret : Option<&Data<T>>) { }
+
+    /// Called before a sys call instruction calls into the host.
+    fn on_syscall(&mut self, _func : Func, _ip : usize) { }
+
+    /// Called when the host closure of a sys call returns, whether or not it failed.
+    fn on_syscall_return(&mut self, _func : Func, _ip : usize) { }
+
+    /// Called when an instruction fails, before the error is converted into an exception.
+    fn on_error(&mut self, _func : Func, _ip : usize, _error : &dyn std::error::Error) { }
+}
+
+/// The tracer used when none is installed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoTracer;
+
+impl<T : Clone, Env> Tracer<T, Env> for NoTracer { }
+
+impl<T : Clone, Env, Tr : Tracer<T, Env> + ?Sized> Tracer<T, Env> for &mut Tr {
+    fn on_start(&mut self, entry : Func) {
+        (**self).on_start(entry)
+    }
+
+    fn on_instr(&mut self, func : Func, ip : usize, instr : &Instr<T, Env>) {
+        (**self).on_instr(func, ip, instr)
+    }
+
+    fn on_branch(&mut self, func : Func, ip : usize, taken : bool) {
+        (**self).on_branch(func, ip, taken)
+    }
+
+    fn on_call(&mut self, from : Func, to : Func, depth : usize) {
+        (**self).on_call(from, to, depth)
+    }
+
+    fn on_return(&mut self, func : Func, ret : Option<&Data<T>>) {
+        (**self).on_return(func, ret)
+    }
+
+    fn on_syscall(&mut self, func : Func, ip : usize) {
+        (**self).on_syscall(func, ip)
+    }
+
+    fn on_syscall_return(&mut self, func : Func, ip : usize) {
+        (**self).on_syscall_return(func, ip)
+    }
+
+    fn on_error(&mut self, func : Func, ip : usize, error : &dyn std::error::Error) {
+        (**self).on_error(func, ip, error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::compile::compile;
+    use crate::vm::{Vm, VmLimits};
+    use crate::R;
+
+    #[derive(Default)]
+    struct Recorder {
+        events : Vec<String>,
+    }
+
+    impl Tracer<u8, ()> for Recorder {
+        fn on_instr(&mut self, func : Func, ip : usize, instr : &Instr<u8, ()>) {
+            self.events.push(format!("instr {} {} {}", func, ip, crate::disasm::disassemble_instr(instr, None)));
+        }
+
+        fn on_call(&mut self, from : Func, to : Func, depth : usize) {
+            self.events.push(format!("call {} {} {}", from, to, depth));
+        }
+
+        fn on_return(&mut self, func : Func, ret : Option<&Data<u8>>) {
+            self.events.push(format!("return {} {:?}", func, ret.map(|ret| matches!(ret, Data::Value(1)))));
+        }
+
+        fn on_syscall(&mut self, func : Func, ip : usize) {
+            self.events.push(format!("syscall {} {}", func, ip));
+        }
+
+        fn on_error(&mut self, func : Func, ip : usize, error : &dyn std::error::Error) {
+            self.events.push(format!("error {} {} {}", func, ip, error));
+        }
+    }
+
+    #[test]
+    fn should_report_execution_to_tracer() -> R<()> {
+        let sym = Symbol(0);
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadFunc(sym, Func(1))
+                           , Instr::Label(Label(0))
+                           , Instr::Call(sym)
+                           ])
+            ,(Func(1), vec![ Instr::SysCall(Box::new(|_, _| Ok(())))
+                           , Instr::LoadValue(sym, 1)
+                           , Instr::Return(sym)
+                           ])
+            ]);
+
+        let mut recorder = Recorder::default();
+        crate::run_traced(&func_defs, &mut (), &mut recorder)?;
+
+        assert_eq!( recorder.events
+                  , vec![ "instr f0 0 load_func s0 f1"
+                        , "instr f0 2 call s0"
+                        , "call f0 f1 1"
+                        , "instr f1 0 sys_call <native>"
+                        , "syscall f1 0"
+                        , "instr f1 1 load_value s0 1"
+                        , "instr f1 2 return s0"
+                        , "return f1 Some(true)"
+                        , "return f0 None"
+                        ]
+                  );
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_errors_to_tracer() -> R<()> {
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::Return(Symbol(3)) ])
+            ]);
+
+        let program = compile(&func_defs)?;
+        let mut vm = Vm::with_tracer(&program, VmLimits::default(), Recorder::default())?;
+
+        assert!( vm.run_to_completion(&mut ()).is_err() );
+        assert_eq!( vm.into_tracer().events
+                  , vec![ "instr f0 0 return s3"
+                        , "error f0 0 symbol 3 does not exist for function 0"
+                        ]
+                  );
+        Ok(())
+    }
+}
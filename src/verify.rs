@@ -0,0 +1,99 @@
+
+use std::collections::{HashMap, HashSet};
+
+use crate::data::*;
+use crate::error::{Diagnostic, VerifyError, VmError};
+
+/// Checks ahead of time for the problems that the vm would otherwise only report when it reaches
+/// the offending instruction.  Every problem found is reported, ordered by function and then by
+/// instruction.
+pub fn verify<T : Clone, Env>( func_defs : &HashMap<Func, Vec<Instr<T, Env>>> ) -> Result<(), VerifyError> {
+
+    let mut diagnostics = vec![];
+
+    if !func_defs.contains_key(&Func(0)) {
+        diagnostics.push(Diagnostic { func: 0, index: None, error: VmError::FunctionDoesNotExist(0) });
+    }
+
+    let mut funcs = func_defs.keys().copied().collect::<Vec<_>>();
+    funcs.sort_by_key(|func| func.0);
+
+    for func in funcs {
+        let instrs = &func_defs[&func];
+
+        let mut labels = HashSet::new();
+        for (index, instr) in instrs.iter().enumerate() {
+            if let Instr::Label(label) = instr {
+                if !labels.insert(*label) {
+                    diagnostics.push(Diagnostic { func: func.0
+                                                , index: Some(index)
+                                                , error: VmError::RedefinitionOfLabel { func: func.0, label: label.0 }
+                                                });
+                }
+            }
+        }
+
+        for (index, instr) in instrs.iter().enumerate() {
+            let error = match instr {
+                Instr::Jump(label) | Instr::BranchOnTrue(label, _) | Instr::Try(label) if !labels.contains(label) =>
+                    VmError::LabelDoesNotExist { func: func.0, label: label.0 },
+                Instr::LoadFunc(_, f) | Instr::MakeClosure(_, f, _) if !func_defs.contains_key(f) =>
+                    VmError::FunctionDoesNotExist(f.0),
+                _ => continue,
+            };
+            diagnostics.push(Diagnostic { func: func.0, index: Some(index), error });
+        }
+    }
+
+    diagnostics.sort_by_key(|diagnostic| (diagnostic.func, diagnostic.index));
+
+    if diagnostics.is_empty() {
+        Ok(())
+    }
+    else {
+        Err(VerifyError { diagnostics })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_accept_valid_program() {
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadFunc(Symbol(0), Func(1))
+                           , Instr::Call(Symbol(0))
+                           ])
+            ,(Func(1), vec![ Instr::Label(Label(0))
+                           , Instr::BranchOnTrue(Label(0), Box::new(|_| Ok(false)))
+                           , Instr::Jump(Label(1))
+                           , Instr::Label(Label(1))
+                           ])
+            ]);
+
+        assert!( verify(&func_defs).is_ok() );
+    }
+
+    #[test]
+    fn should_report_every_problem() {
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(1), vec![ Instr::Label(Label(0))
+                           , Instr::Jump(Label(3))
+                           , Instr::Label(Label(0))
+                           , Instr::LoadFunc(Symbol(0), Func(7))
+                           ])
+            ,(Func(2), vec![ Instr::BranchOnTrue(Label(0), Box::new(|_| Ok(false)))
+                           ])
+            ]);
+
+        let diagnostics = verify(&func_defs).unwrap_err().diagnostics;
+
+        assert_eq!( diagnostics.len(), 5 );
+        assert!( matches!( diagnostics[0], Diagnostic { func: 0, index: None, error: VmError::FunctionDoesNotExist(0) } ) );
+        assert!( matches!( diagnostics[1], Diagnostic { func: 1, index: Some(1), error: VmError::LabelDoesNotExist { func: 1, label: 3 } } ) );
+        assert!( matches!( diagnostics[2], Diagnostic { func: 1, index: Some(2), error: VmError::RedefinitionOfLabel { func: 1, label: 0 } } ) );
+        assert!( matches!( diagnostics[3], Diagnostic { func: 1, index: Some(3), error: VmError::FunctionDoesNotExist(7) } ) );
+        assert!( matches!( diagnostics[4], Diagnostic { func: 2, index: Some(0), error: VmError::LabelDoesNotExist { func: 2, label: 0 } } ) );
+    }
+}
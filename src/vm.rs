@@ -0,0 +1,1455 @@
+
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::task::Poll;
+
+use crate::error::{VmError, TraceFrame};
+use crate::data::*;
+use crate::compile::{CompiledProgram, CompiledFunc, Op, Target};
+use crate::trace::{Tracer, NoTracer};
+use crate::replay::{Event, SysCallLog, Writes};
+use crate::R;
+
+#[derive(Debug, Clone)]
+pub enum Status<T : Clone> {
+    Running,
+    Finished(Option<Data<T>>),
+    /// The fuel budget ran out before the next instruction.  Refuel the vm to continue.
+    OutOfFuel,
+    /// A sys call is waiting on the host.  Complete it to continue.
+    Pending,
+}
+
+/// Bounds on the resources a running program may use.  None means unbounded.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VmLimits {
+    /// Maximum number of suspended frames on the call stack.
+    pub max_frames : Option<usize>,
+    /// Maximum number of values on the param stack.
+    pub max_params : Option<usize>,
+    /// Maximum number of distinct symbols in a single frame's locals.
+    pub max_locals : Option<usize>,
+}
+
+// NOTE:  A handler belongs to the frame at depth and catches by unwinding to that frame, resetting
+// the params to their length when the try region started and jumping to target.
+#[derive(Debug, Clone)]
+pub(crate) struct Handler {
+    depth : usize,
+    target : usize,
+    params : usize,
+}
+
+type ErrorConverter<'a, T> = Box<dyn Fn(Box<dyn std::error::Error>) -> Data<T> + 'a>;
+
+// NOTE:  Whether the results of host closures are being logged, or taken from a log instead of
+// calling the closures.
+enum Log<T : Clone> {
+    Off,
+    Record(Vec<Event<T>>),
+    Replay(std::vec::IntoIter<Event<T>>),
+}
+
+// NOTE:  A frame is pushed with the caller's state when a function is entered, and is also the
+// continuation saved by a coroutine.  Resume is the caller's slot that receives the coroutine
+// when the function it resumed yields or returns.  Handlers are only saved by a coroutine, for
+// the try regions it had open when it yielded.
+#[derive(Debug, Clone)]
+pub(crate) struct Frame<T : Clone> {
+    pub(crate) instr_ptr : usize,
+    pub(crate) locals : Locals<T>,
+    pub(crate) current_function : Func,
+    pub(crate) resume : Option<Slot>,
+    pub(crate) handlers : Vec<Handler>,
+}
+
+/// An active frame as seen from outside the vm.
+pub struct FrameView<'b, T : Clone> {
+    pub func : Func,
+    /// Index of the source instruction that is executing, or the call site for callers.
+    pub instr_ptr : usize,
+    pub locals : &'b Locals<T>,
+}
+
+pub struct Vm<'a, T : Clone, Env, Tr = NoTracer> {
+    program : &'a CompiledProgram<'a, T, Env>,
+    stack : Vec<Frame<T>>,
+    current_function : Func,
+    // NOTE:  The compiled current_function, so that stepping needs no lookup.
+    func : &'a CompiledFunc<'a, T, Env>,
+    instr_ptr : usize,
+    locals : Locals<T>,
+    params : Vec<Data<T>>,
+    ret : Option<Data<T>>,
+    finished : bool,
+    fuel : Option<u64>,
+    fuel_consumed : HashMap<Func, u64>,
+    limits : VmLimits,
+    handlers : Vec<Handler>,
+    exception : Option<Data<T>>,
+    error_converter : Option<ErrorConverter<'a, T>>,
+    pending : Option<Slot>,
+    future : Option<SysCallFuture<T>>,
+    log : Log<T>,
+    // NOTE:  Symbols written in each function while a debugger watches.  Writes are noted by the
+    // locals and moved here when the vm switches to other locals.
+    journal : Option<Vec<(Func, Symbol)>>,
+    tracer : Tr,
+}
+
+impl<'a, T : Clone, Env> Vm<'a, T, Env> {
+    pub fn new( program : &'a CompiledProgram<'a, T, Env> ) -> R<Self> {
+        Vm::with_limits(program, VmLimits::default())
+    }
+
+    pub fn with_limits( program : &'a CompiledProgram<'a, T, Env>, limits : VmLimits ) -> R<Self> {
+        Vm::with_tracer(program, limits, NoTracer)
+    }
+}
+
+impl<'a, T : Clone, Env, Tr : Tracer<T, Env>> Vm<'a, T, Env, Tr> {
+    pub fn with_tracer( program : &'a CompiledProgram<'a, T, Env>, limits : VmLimits, tracer : Tr ) -> R<Self> {
+        let current_function = Func(0);
+
+        let func = match program.func(&current_function) {
+            Some(func) => func,
+            None => return Err(Box::new(VmError::FunctionDoesNotExist(0))),
+        };
+
+        let mut tracer = tracer;
+        tracer.on_start(current_function);
+
+        Ok(Vm { program
+              , stack: vec![]
+              , current_function
+              , func
+              , instr_ptr: 0
+              , locals: Locals::with_slots(current_function.0, func.slot_map.clone(), limits.max_locals)
+              , params: vec![]
+              , ret: None
+              , finished: false
+              , fuel: None
+              , fuel_consumed: HashMap::new()
+              , limits
+              , handlers: vec![]
+              , exception: None
+              , error_converter: None
+              , pending: None
+              , future: None
+              , log: Log::Off
+              , journal: None
+              , tracer
+              })
+    }
+
+    pub fn current_function(&self) -> Func { self.current_function }
+    /// Index of the next source instruction to execute in the current function.
+    pub fn instr_ptr(&self) -> usize {
+        self.func.source_index(self.instr_ptr)
+    }
+    pub fn locals(&self) -> &Locals<T> { &self.locals }
+    pub fn params(&self) -> &[Data<T>] { &self.params }
+    pub fn ret(&self) -> Option<&Data<T>> { self.ret.as_ref() }
+    pub fn depth(&self) -> usize { self.stack.len() }
+    pub fn is_finished(&self) -> bool { self.finished }
+    pub fn is_pending(&self) -> bool { self.pending.is_some() }
+    pub fn limits(&self) -> &VmLimits { &self.limits }
+    pub fn program(&self) -> &'a CompiledProgram<'a, T, Env> { self.program }
+    pub fn tracer(&self) -> &Tr { &self.tracer }
+    pub fn tracer_mut(&mut self) -> &mut Tr { &mut self.tracer }
+    pub fn into_tracer(self) -> Tr { self.tracer }
+
+    /// The active frames, innermost first.
+    pub fn frames(&self) -> Vec<FrameView<'_, T>> {
+        let frame = |func : Func, instr_ptr : usize, locals| {
+            // NOTE:  Every function on the stack was checked to be in the program when called.
+            let instr_ptr = self.program.func(&func).unwrap().source_index(instr_ptr);
+            FrameView { func, instr_ptr, locals }
+        };
+
+        let mut frames = vec![frame(self.current_function, self.instr_ptr, &self.locals)];
+        for caller in self.stack.iter().rev() {
+            frames.push(frame(caller.current_function, caller.instr_ptr - 1, &caller.locals));
+        }
+        frames
+    }
+
+    /// The active frames, innermost first.  Callers report the index of their call instruction.
+    pub fn stack_trace(&self) -> Vec<TraceFrame> {
+        let debug_info = self.program.debug_info();
+        self.frames().into_iter().map(|frame| {
+            TraceFrame { func: frame.func.0
+                       , instr_ptr: frame.instr_ptr
+                       , name: debug_info.and_then(|info| info.func_name(&frame.func)).map(|name| name.to_string())
+                       , span: debug_info.and_then(|info| info.span(&frame.func, frame.instr_ptr))
+                       }
+        }).collect()
+    }
+
+    /// The exception most recently thrown and not yet caught.
+    pub fn exception(&self) -> Option<&Data<T>> { self.exception.as_ref() }
+
+    /// Lets try regions catch errors from closures and the vm itself.  The converter turns the
+    /// error into the data bound by Catch.  Without a converter errors always abort the vm.
+    pub fn catch_errors(&mut self, converter : impl Fn(Box<dyn std::error::Error>) -> Data<T> + 'a) {
+        self.error_converter = Some(Box::new(converter));
+    }
+
+    /// Remaining fuel, or None when the vm is not metered.
+    pub fn fuel(&self) -> Option<u64> { self.fuel }
+
+    /// Fuel consumed by each function since metering started.
+    pub fn fuel_consumed(&self) -> &HashMap<Func, u64> { &self.fuel_consumed }
+
+    /// Meters the vm so that every executed instruction consumes one unit of fuel.  None removes
+    /// the budget.
+    pub fn set_fuel(&mut self, fuel : Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// Adds fuel to a metered vm so that it can continue after Status::OutOfFuel.
+    pub fn refuel(&mut self, fuel : u64) {
+        self.fuel = Some(self.fuel.unwrap_or(0).saturating_add(fuel));
+    }
+
+    /// Finishes the pending sys call with its result.  The vm continues with the instruction after
+    /// the sys call on its next step.
+    pub fn complete(&mut self, data : Data<T>) -> R<()> {
+        self.future = None;
+        match self.pending.take() {
+            Some(slot) => {
+                if let Log::Record(events) = &mut self.log {
+                    events.push(Event::Value(data.clone(), vec![]));
+                }
+                self.locals.set_slot(slot, data)?;
+                self.instr_ptr += 1;
+                Ok(())
+            },
+            None => Err(Box::new(VmError::NoPendingSysCall { func: self.current_function.0 })),
+        }
+    }
+
+    /// Fails the pending sys call with the error of its future.  Like the error of any sys call it
+    /// is raised to the innermost try region when the vm catches errors, and returned otherwise.
+    pub fn fail(&mut self, error : Box<dyn std::error::Error>) -> R<()> {
+        self.future = None;
+        if self.pending.take().is_none() {
+            return Err(Box::new(VmError::NoPendingSysCall { func: self.current_function.0 }));
+        }
+        if let Log::Record(events) = &mut self.log {
+            events.push(Event::Error(error.to_string(), vec![]));
+        }
+        self.recover(error)
+    }
+
+    /// The future of the pending async sys call.  Complete the vm with its output to continue.
+    /// A sys call that answered pending without a future has none.
+    pub fn take_future(&mut self) -> Option<SysCallFuture<T>> {
+        self.future.take()
+    }
+
+    // NOTE:  Notes the symbols written from now on, for watchpoints.
+    pub(crate) fn start_journal(&mut self) {
+        self.journal = Some(vec![]);
+        self.locals.start_journal();
+    }
+
+    /// Stops noting writes and returns the symbols written since the journal started, with the
+    /// function whose frame each was written in.
+    pub(crate) fn take_journal(&mut self) -> Vec<(Func, Symbol)> {
+        let mut journal = self.journal.take().unwrap_or_default();
+        let func = self.current_function;
+        journal.extend(self.locals.take_journal().into_iter().map(|sym| (func, sym)));
+        journal
+    }
+
+    /// Logs branch decisions and the results of sys calls and native functions from now on.
+    pub fn start_recording(&mut self) {
+        self.log = Log::Record(vec![]);
+    }
+
+    /// Stops recording and returns the log, or None when the vm was not recording.
+    pub fn take_recording(&mut self) -> Option<SysCallLog<T>> {
+        match std::mem::replace(&mut self.log, Log::Off) {
+            Log::Record(events) => Some(SysCallLog { events }),
+            log => {
+                self.log = log;
+                None
+            },
+        }
+    }
+
+    /// Takes branch decisions and the results of sys calls and native functions from the log
+    /// instead of calling their closures.  The run fails with ReplayDiverged when the program
+    /// asks for a result the log does not have next, or finishes before the log is used up.
+    pub fn replay(&mut self, log : SysCallLog<T>) {
+        self.log = Log::Replay(log.events.into_iter());
+    }
+
+    /// Runs until the program finishes or the vm suspends.
+    pub fn run_to_completion(&mut self, env : &mut Env) -> R<Status<T>> {
+        loop {
+            match self.step(env)? {
+                Status::Running => { },
+                status => return Ok(status),
+            }
+        }
+    }
+
+    pub fn run_for(&mut self, steps : usize, env : &mut Env) -> R<Status<T>> {
+        for _ in 0..steps {
+            match self.step(env)? {
+                Status::Running => { },
+                status => return Ok(status),
+            }
+        }
+        Ok(self.status())
+    }
+
+    /// Executes a single instruction.  Falling off the end of a function is not counted as an
+    /// instruction, so the step which follows it executes the next instruction of the caller.
+    pub fn step(&mut self, env : &mut Env) -> R<Status<T>> {
+        match self.execute(env) {
+            Ok(Status::Finished(ret)) => {
+                self.check_replay_finished()?;
+                Ok(Status::Finished(ret))
+            },
+            Ok(status) => Ok(status),
+            Err(error) => {
+                self.recover(error)?;
+                Ok(self.status())
+            },
+        }
+    }
+
+    // NOTE:  Raises the error as an exception when the vm catches errors and a try region is
+    // open, otherwise returns it.
+    fn recover(&mut self, error : Box<dyn std::error::Error>) -> R<()> {
+        self.tracer.on_error(self.current_function, self.instr_ptr(), error.as_ref());
+        match &self.error_converter {
+            Some(converter) if !self.handlers.is_empty() => {
+                let exception = converter(error);
+                self.raise(exception)
+            },
+            _ => Err(error),
+        }
+    }
+
+    fn execute(&mut self, env : &mut Env) -> R<Status<T>> {
+        if self.finished || self.pending.is_some() {
+            return Ok(self.status());
+        }
+
+        if !self.leave_ended_functions() {
+            return Ok(self.status());
+        }
+        let func = self.func;
+
+        if let Some(fuel) = self.fuel {
+            if fuel == 0 {
+                return Ok(Status::OutOfFuel);
+            }
+            self.fuel = Some(fuel - 1);
+            *self.fuel_consumed.entry(self.current_function).or_insert(0) += 1;
+        }
+
+        let ip = func.source_index(self.instr_ptr);
+        self.tracer.on_instr(self.current_function, ip, &func.source[ip]);
+
+        match &func.ops[self.instr_ptr] {
+            Op::Jump(target) => { self.instr_ptr = self.offset(*target)?; },
+            Op::BranchOnTrue(target, f) => {
+                let taken = self.host( |vm| f(&vm.locals)
+                                     , |_, taken, _| Event::Branch(*taken)
+                                     , |_, event| match event { Event::Branch(taken) => Some((taken, vec![])), _ => None }
+                                     )?;
+                self.tracer.on_branch(self.current_function, ip, taken);
+                if taken {
+                    self.instr_ptr = self.offset(*target)?;
+                }
+                else {
+                    self.instr_ptr += 1;
+                }
+            },
+            Op::Return(slot) => {
+                self.ret = Some(self.locals.get_slot(*slot)?);
+                self.pop_frame(true);
+            },
+            Op::LoadValue(slot, data) => {
+                self.locals.set_slot(*slot, Data::Value((*data).clone()))?;
+                self.instr_ptr += 1;
+            },
+            Op::LoadFromReturn(slot) => {
+                match self.ret {
+                    Some(ref ret) => {
+                        self.locals.set_slot(*slot, ret.clone())?;
+                        self.instr_ptr += 1;
+                    },
+                    None => return Err(Box::new(VmError::ReturnNotSet { func: self.current_function.0, sym: func.symbol(*slot).0 })),
+                }
+            },
+            Op::Call(slot) => {
+                let target = self.locals.get_slot(*slot)?;
+                self.call(target, false, env)?;
+            },
+            Op::TailCall(slot) => {
+                let target = self.locals.get_slot(*slot)?;
+                self.call(target, true, env)?;
+            },
+            Op::Yield(slot) => {
+                let resume = match self.stack.last() {
+                    Some(Frame { resume: Some(resume), .. }) => *resume,
+                    _ => return Err(Box::new(VmError::YieldOutsideCoroutine { func: self.current_function.0 })),
+                };
+                let value = self.locals.get_slot(*slot)?;
+                // NOTE:  The try regions of the coroutine are on top of the handlers.  They are
+                // restored on top of the resumer's when it continues.
+                let depth = self.stack.len();
+                let open = self.handlers.iter().position(|handler| handler.depth >= depth).unwrap_or(self.handlers.len());
+                let continuation = Frame { instr_ptr: self.instr_ptr + 1
+                                         , locals: self.locals.clone()
+                                         , current_function: self.current_function
+                                         , resume: None
+                                         , handlers: self.handlers.split_off(open)
+                                         };
+                self.ret = Some(value);
+                self.pop_frame(true);
+                self.locals.set_slot(resume, Data::Coroutine(Coroutine { frame: Some(Rc::new(continuation)) }))?;
+            },
+            Op::Resume(slot) => {
+                match self.locals.get_slot(*slot)? {
+                    Data::Coroutine(Coroutine { frame: Some(frame) }) => {
+                        let Frame { instr_ptr, locals, current_function, handlers, .. } = (*frame).clone();
+                        let compiled = match self.program.func(&current_function) {
+                            Some(compiled) => compiled,
+                            None => return Err(Box::new(VmError::FunctionDoesNotExist(current_function.0))),
+                        };
+                        self.push_frame(current_function, compiled, locals, Some(*slot))?;
+                        self.instr_ptr = instr_ptr;
+                        // NOTE:  Params on the stack at the yield were left to the resumer, so a
+                        // restored try region only drops the params pushed since the resume.
+                        for handler in handlers {
+                            self.handlers.push(Handler { depth: self.stack.len(), target: handler.target, params: self.params.len() });
+                        }
+                    },
+                    Data::Coroutine(Coroutine { frame: None }) =>
+                        return Err(Box::new(VmError::ResumeOfFinishedCoroutine { func: self.current_function.0, sym: func.symbol(*slot).0 })),
+                    // NOTE:  A native finishes when called, so it cannot be a coroutine.
+                    target @ (Data::Func(_) | Data::Closure(..) | Data::Partial(..)) if !is_native(&target) => {
+                        self.call(target, false, env)?;
+                        // NOTE:  Calling a function always pushes the resumer's frame.
+                        self.stack.last_mut().unwrap().resume = Some(*slot);
+                    },
+                    _ => return Err(Box::new(VmError::AttemptToResumeNonCoroutine { current_func: self.current_function.0 })),
+                }
+            },
+            Op::MakeClosure(slot, f, captures) => {
+                let captures = captures.iter()
+                                       .map(|(sym, capture)| Ok((*sym, self.locals.get_slot(*capture)?)))
+                                       .collect::<R<Vec<_>>>()?;
+                self.locals.set_slot(*slot, Data::Closure(*f, Rc::new(captures)))?;
+                self.instr_ptr += 1;
+            },
+            Op::Apply(slot, f, args) => {
+                // NOTE:  Applying a partial binds more arguments to its callee rather than wrapping
+                // it, so the callee of a partial is never itself a partial.
+                let mut bound = match self.locals.get_slot(*f)? {
+                    Data::Partial(f, bound) => (f, bound.to_vec()),
+                    f @ (Data::Func(_) | Data::Closure(..) | Data::Native(_)) => (Rc::new(f), vec![]),
+                    _ => return Err(Box::new(VmError::AttemptToApplyNonFunction { current_func: self.current_function.0 })),
+                };
+                for arg in args {
+                    bound.1.push(self.locals.get_slot(*arg)?);
+                }
+                self.locals.set_slot(*slot, Data::Partial(bound.0, Rc::new(bound.1)))?;
+                self.instr_ptr += 1;
+            },
+            Op::Try(target) => {
+                let target = self.offset(*target)?;
+                self.handlers.push(Handler { depth: self.stack.len(), target, params: self.params.len() });
+                self.instr_ptr += 1;
+            },
+            Op::EndTry => {
+                match self.handlers.last() {
+                    Some(handler) if handler.depth == self.stack.len() => { self.handlers.pop(); },
+                    _ => return Err(Box::new(VmError::EndTryWithoutTry { func: self.current_function.0 })),
+                }
+                self.instr_ptr += 1;
+            },
+            Op::Throw(slot) => {
+                let exception = self.locals.get_slot(*slot)?;
+                self.raise(exception)?;
+            },
+            Op::Catch(slot) => {
+                match self.exception.take() {
+                    Some(exception) => self.locals.set_slot(*slot, exception)?,
+                    None => return Err(Box::new(VmError::ExceptionNotSet { func: self.current_function.0, sym: func.symbol(*slot).0 })),
+                }
+                self.instr_ptr += 1;
+            },
+            Op::PushParam(slot) => {
+                let param = self.locals.get_slot(*slot)?;
+                self.push_param(param)?;
+                self.instr_ptr += 1;
+            },
+            Op::PopParam(slot) => {
+                match self.params.pop() {
+                    Some(param) => self.locals.set_slot(*slot, param)?,
+                    None => return Err(Box::new(VmError::AttemptToPopEmptyParams { current_func: self.current_function.0, sym: func.symbol(*slot).0 })),
+                }
+                self.instr_ptr += 1;
+            },
+            Op::LoadFromExec(slot, f) => {
+                let result = f(&self.locals)?;
+                self.locals.set_slot(*slot, result)?;
+                self.instr_ptr += 1;
+            },
+            Op::LoadFunc(slot, f) => {
+                self.locals.set_slot(*slot, Data::Func(*f))?;
+                self.instr_ptr += 1;
+            },
+            Op::LoadNative(slot, n) => {
+                self.locals.set_slot(*slot, Data::Native(*n))?;
+                self.instr_ptr += 1;
+            },
+            Op::SysCall(f) => {
+                self.tracer.on_syscall(self.current_function, ip);
+                let result = self.host( |vm| f(&mut vm.locals, env)
+                                      , |_, _, writes| Event::SysCall(writes)
+                                      , |_, event| match event { Event::SysCall(writes) => Some(((), writes)), _ => None }
+                                      );
+                self.tracer.on_syscall_return(self.current_function, ip);
+                result?;
+                self.instr_ptr += 1;
+            },
+            Op::LoadFromSysCall(slot, f) => {
+                self.tracer.on_syscall(self.current_function, ip);
+                let result = self.host( |vm| f(&mut vm.locals, env)
+                                      , |_, data, writes| Event::Value(data.clone(), writes)
+                                      , |_, event| match event { Event::Value(data, writes) => Some((data, writes)), _ => None }
+                                      );
+                self.tracer.on_syscall_return(self.current_function, ip);
+                self.locals.set_slot(*slot, result?)?;
+                self.instr_ptr += 1;
+            },
+            Op::LoadFromPollSysCall(slot, f) => {
+                self.tracer.on_syscall(self.current_function, ip);
+                let result = self.host(|vm| f(&mut vm.locals, env), ready_event, ready_data);
+                self.tracer.on_syscall_return(self.current_function, ip);
+                self.load_polled(*slot, result?)?;
+            },
+            Op::LoadFromAsyncSysCall(slot, f) => {
+                self.tracer.on_syscall(self.current_function, ip);
+                let result = self.host( |vm| {
+                                            vm.future = Some(f(&mut vm.locals, env));
+                                            Ok(Poll::Pending)
+                                        }
+                                      , ready_event
+                                      , ready_data
+                                      );
+                self.tracer.on_syscall_return(self.current_function, ip);
+                self.load_polled(*slot, result?)?;
+            },
+        }
+
+        Ok(self.status())
+    }
+
+    // NOTE:  Calls into the host unless replaying, in which case the result is the next event of
+    // the log.  While recording, the symbols the call writes are noted and logged with its result.
+    fn host<V>( &mut self
+              , call : impl FnOnce(&mut Self) -> R<V>
+              , record : impl FnOnce(&Self, &V, Writes<T>) -> Event<T>
+              , replay : impl FnOnce(&mut Self, Event<T>) -> Option<(V, Writes<T>)>
+              ) -> R<V> {
+        match self.log {
+            Log::Off => call(self),
+            Log::Record(_) => {
+                let journaling = self.locals.is_journaling();
+                let from = self.locals.start_journal();
+                let result = call(self);
+                let writes = self.locals.written_since(from)
+                                        .into_iter()
+                                        .map(|sym| (sym, self.locals.get_ref(&sym).ok().cloned()))
+                                        .collect();
+                if !journaling {
+                    self.locals.take_journal();
+                }
+                let event = match &result {
+                    Ok(value) => record(self, value, writes),
+                    Err(error) => Event::Error(error.to_string(), writes),
+                };
+                if let Log::Record(events) = &mut self.log {
+                    events.push(event);
+                }
+                result
+            },
+            Log::Replay(_) => self.replayed(replay),
+        }
+    }
+
+    fn replayed<V>( &mut self, replay : impl FnOnce(&mut Self, Event<T>) -> Option<(V, Writes<T>)> ) -> R<V> {
+        let func = self.current_function.0;
+        let event = match &mut self.log {
+            Log::Replay(events) => events.next(),
+            _ => None,
+        };
+        let (value, writes) = match event {
+            Some(Event::Error(message, writes)) => {
+                self.write(writes)?;
+                return Err(message.into());
+            },
+            Some(event) => replay(self, event).ok_or(VmError::ReplayDiverged { func })?,
+            None => return Err(Box::new(VmError::ReplayDiverged { func })),
+        };
+        self.write(writes)?;
+        Ok(value)
+    }
+
+    fn check_replay_finished(&self) -> R<()> {
+        match &self.log {
+            Log::Replay(events) if events.len() > 0 => Err(Box::new(VmError::ReplayDiverged { func: self.current_function.0 })),
+            _ => Ok(()),
+        }
+    }
+
+    fn write(&mut self, writes : Writes<T>) -> R<()> {
+        for (sym, data) in writes {
+            match data {
+                Some(data) => self.locals.set(&sym, data)?,
+                None => { let _ = self.locals.take(&sym); },
+            }
+        }
+        Ok(())
+    }
+
+    fn offset(&self, target : Target) -> R<usize> {
+        match target {
+            Target::Offset(offset) => Ok(offset),
+            Target::Missing(label) => Err(Box::new(VmError::LabelDoesNotExist { func: self.current_function.0, label: label.0 })),
+        }
+    }
+
+    fn load_polled(&mut self, slot : Slot, result : Poll<Data<T>>) -> R<()> {
+        // NOTE:  A replayed sys call which answered pending is completed by the next event.
+        let result = match result {
+            Poll::Pending if matches!(self.log, Log::Replay(_)) =>
+                Poll::Ready(self.replayed(|_, event| match event { Event::Value(data, writes) => Some((data, writes)), _ => None })?),
+            result => result,
+        };
+        match result {
+            Poll::Ready(result) => {
+                self.locals.set_slot(slot, result)?;
+                self.instr_ptr += 1;
+            },
+            Poll::Pending => { self.pending = Some(slot); },
+        }
+        Ok(())
+    }
+
+    // NOTE:  A tail call replaces the current frame instead of pushing a new one, so the callee
+    // returns to our caller.
+    fn call(&mut self, target : Data<T>, tail : bool, env : &mut Env) -> R<()> {
+        match target {
+            Data::Func(f) => self.enter(f, tail)?,
+            Data::Closure(f, captures) => {
+                self.enter(f, tail)?;
+                for (sym, data) in captures.iter() {
+                    self.locals.set(sym, data.clone())?;
+                }
+            },
+            Data::Partial(f, args) => {
+                for arg in args.iter().rev() {
+                    self.push_param(arg.clone())?;
+                }
+                self.call((*f).clone(), tail, env)?;
+            },
+            Data::Native(n) => {
+                let f = match self.program.native(&n) {
+                    Some(f) => f,
+                    None => return Err(Box::new(VmError::NativeDoesNotExist(n.0))),
+                };
+                // NOTE:  The native's error is kept apart from the host's, so that the params it
+                // popped before failing are logged as well.
+                let depth = self.params.len();
+                let (result, _) = self.host( |vm| {
+                                                 let mut params = Params::new(&mut vm.params);
+                                                 let result = f(&mut params, env);
+                                                 Ok((result, params.low()))
+                                             }
+                                           , |vm, (result, low), _| {
+                                                 let result = match result {
+                                                     Ok(ret) => Ok(ret.clone()),
+                                                     Err(error) => Err(error.to_string()),
+                                                 };
+                                                 Event::Native(result, depth - low, vm.params[*low..].to_vec())
+                                             }
+                                           , |vm, event| match event {
+                                                 Event::Native(result, popped, pushed) => {
+                                                     vm.params.truncate(depth.saturating_sub(popped));
+                                                     vm.params.extend(pushed);
+                                                     Some(((result.map_err(|message| message.into()), 0), vec![]))
+                                                 },
+                                                 _ => None,
+                                             }
+                                           )?;
+                let ret = result?;
+                // NOTE:  The native may have pushed past the limit, so its extra params are
+                // dropped to keep the params within it.
+                if let Err(error) = self.check_params(self.params.len()) {
+                    self.params.truncate(self.limits.max_params.unwrap_or(usize::MAX));
+                    return Err(error);
+                }
+                self.ret = Some(ret);
+                if tail {
+                    self.pop_frame(true);
+                }
+                else {
+                    self.instr_ptr += 1;
+                }
+            },
+            _ => return Err(Box::new(VmError::AttemptToCallNonFunction { current_func: self.current_function.0 })),
+        }
+        Ok(())
+    }
+
+    // NOTE:  Pushes the current frame and starts executing f with fresh locals.  The current
+    // instruction is expected to be the call.
+    fn enter(&mut self, f : Func, tail : bool) -> R<()> {
+        let func = match self.program.func(&f) {
+            Some(func) => func,
+            None => return Err(Box::new(VmError::FunctionDoesNotExist(f.0))),
+        };
+
+        if tail {
+            self.tracer.on_return(self.current_function, None);
+            self.tracer.on_call(self.current_function, f, self.stack.len());
+            self.swap_locals(Locals::with_slots(f.0, func.slot_map.clone(), self.limits.max_locals));
+            // NOTE:  Try regions opened by the replaced function end with it.
+            let depth = self.stack.len();
+            self.handlers.retain(|handler| handler.depth < depth);
+            self.current_function = f;
+            self.func = func;
+            self.instr_ptr = 0;
+            return Ok(());
+        }
+
+        self.push_frame(f, func, Locals::with_slots(f.0, func.slot_map.clone(), self.limits.max_locals), None)?;
+        self.instr_ptr = 0;
+        Ok(())
+    }
+
+    // NOTE:  Saves the caller and switches to f with the given locals.  The caller sets where f
+    // starts.
+    fn push_frame(&mut self, f : Func, func : &'a CompiledFunc<'a, T, Env>, locals : Locals<T>, resume : Option<Slot>) -> R<()> {
+        if let Some(max) = self.limits.max_frames {
+            if self.stack.len() >= max {
+                return Err(Box::new(VmError::StackOverflow { func: f.0, depth: self.stack.len() + 1 }));
+            }
+        }
+
+        let old_locals = self.swap_locals(locals);
+
+        self.stack.push(Frame { instr_ptr: self.instr_ptr + 1
+                              , locals: old_locals
+                              , current_function: self.current_function
+                              , resume
+                              , handlers: vec![]
+                              });
+
+        self.tracer.on_call(self.current_function, f, self.stack.len());
+        self.current_function = f;
+        self.func = func;
+        Ok(())
+    }
+
+    // NOTE:  Moves the writes noted in the current locals to the journal before they are left.
+    // Locals saved by Yield were cloned with the journal of the yielding frame, so the journal
+    // of the incoming locals starts over.
+    fn swap_locals(&mut self, mut locals : Locals<T>) -> Locals<T> {
+        locals.take_journal();
+        if let Some(journal) = &mut self.journal {
+            let func = self.current_function;
+            journal.extend(self.locals.take_journal().into_iter().map(|sym| (func, sym)));
+            locals.start_journal();
+        }
+        std::mem::replace(&mut self.locals, locals)
+    }
+
+    // NOTE:  Unwinds to the innermost handler.  When there is none the vm is left where the
+    // exception was thrown.
+    fn raise(&mut self, exception : Data<T>) -> R<()> {
+        self.exception = Some(exception);
+
+        let handler = match self.handlers.pop() {
+            Some(handler) => handler,
+            None => return Err(Box::new(VmError::UncaughtException { func: self.current_function.0 })),
+        };
+
+        while self.stack.len() > handler.depth {
+            self.pop_frame(false);
+        }
+        self.params.truncate(handler.params);
+        self.instr_ptr = handler.target;
+        Ok(())
+    }
+
+    fn push_param(&mut self, param : Data<T>) -> R<()> {
+        self.check_params(self.params.len() + 1)?;
+        self.params.push(param);
+        Ok(())
+    }
+
+    fn check_params(&self, depth : usize) -> R<()> {
+        match self.limits.max_params {
+            Some(max) if depth > max => Err(Box::new(VmError::ParamStackOverflow { func: self.current_function.0, depth })),
+            _ => Ok(()),
+        }
+    }
+
+    // NOTE:  Returns functions whose instruction pointer ran off their end.  Returns false when
+    // that finished the vm.
+    pub(crate) fn leave_ended_functions(&mut self) -> bool {
+        if self.finished {
+            return false;
+        }
+        while self.func.ops.len() <= self.instr_ptr {
+            if !self.pop_frame(false) {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn status(&self) -> Status<T> {
+        if self.finished {
+            Status::Finished(self.ret.clone())
+        }
+        else if self.pending.is_some() {
+            Status::Pending
+        }
+        else {
+            Status::Running
+        }
+    }
+
+    // NOTE:  Returns false when there is no frame left to return to, at which point the vm is
+    // finished.
+    fn pop_frame(&mut self, returned : bool) -> bool {
+        self.tracer.on_return(self.current_function, if returned { self.ret.as_ref() } else { None });
+        match self.stack.pop() {
+            Some(Frame { instr_ptr, locals, current_function, resume, .. }) => {
+                self.instr_ptr = instr_ptr;
+                self.swap_locals(locals);
+                self.current_function = current_function;
+                // NOTE:  Every function on the stack was in the program when it was called.
+                self.func = self.program.func(&current_function).unwrap();
+                // NOTE:  A coroutine that returns is finished.  The resumer's slot held the
+                // coroutine, so setting it cannot grow the locals.
+                if let Some(resume) = resume {
+                    self.locals.set_slot(resume, Data::Coroutine(Coroutine { frame: None })).unwrap();
+                }
+                // NOTE:  Try regions left open by the returning function end with it.
+                let depth = self.stack.len();
+                self.handlers.retain(|handler| handler.depth <= depth);
+                true
+            },
+            None => {
+                self.finished = true;
+                false
+            },
+        }
+    }
+}
+
+fn is_native<T : Clone>( data : &Data<T> ) -> bool {
+    match data {
+        Data::Native(_) => true,
+        Data::Partial(f, _) => is_native(f),
+        _ => false,
+    }
+}
+
+fn ready_event<T : Clone, S>( _ : &S, result : &Poll<Data<T>>, writes : Writes<T> ) -> Event<T> {
+    match result {
+        Poll::Ready(data) => Event::Value(data.clone(), writes),
+        Poll::Pending => Event::Pending(writes),
+    }
+}
+
+fn ready_data<T : Clone, S>( _ : &mut S, event : Event<T> ) -> Option<(Poll<Data<T>>, Writes<T>)> {
+    match event {
+        Event::Value(data, writes) => Some((Poll::Ready(data), writes)),
+        Event::Pending(writes) => Some((Poll::Pending, writes)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::compile;
+
+    #[test]
+    fn should_step_one_instruction_at_a_time() -> R<()> {
+        let sym = Symbol(0);
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadValue(sym, 1)
+                           , Instr::LoadValue(sym, 2)
+                           , Instr::Return(sym)
+                           ])
+            ]);
+
+        let program = compile(&func_defs)?;
+        let mut vm = Vm::new(&program)?;
+
+        assert!( matches!( vm.step(&mut ())?, Status::Running ) );
+        assert!( matches!( vm.locals().get(&sym)?, Data::Value(1) ) );
+        assert_eq!( vm.instr_ptr(), 1 );
+
+        assert!( matches!( vm.step(&mut ())?, Status::Running ) );
+        assert!( matches!( vm.locals().get(&sym)?, Data::Value(2) ) );
+
+        assert!( matches!( vm.step(&mut ())?, Status::Finished(Some(Data::Value(2))) ) );
+        assert!( vm.is_finished() );
+        assert!( matches!( vm.step(&mut ())?, Status::Finished(Some(Data::Value(2))) ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_pause_and_resume_with_run_for() -> R<()> {
+        let sym = Symbol(0);
+        let f = Symbol(1);
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadFunc(f, Func(1))
+                           , Instr::Call(f)
+                           , Instr::LoadFromReturn(sym)
+                           , Instr::Return(sym)
+                           ])
+            ,(Func(1), vec![ Instr::LoadValue(sym, 3)
+                           , Instr::Return(sym)
+                           ])
+            ]);
+
+        let program = compile(&func_defs)?;
+        let mut vm = Vm::new(&program)?;
+
+        assert!( matches!( vm.run_for(3, &mut ())?, Status::Running ) );
+        assert_eq!( vm.current_function(), Func(1) );
+        assert_eq!( vm.depth(), 1 );
+
+        assert!( matches!( vm.run_for(1, &mut ())?, Status::Running ) );
+        assert_eq!( vm.current_function(), Func(0) );
+        assert!( matches!( vm.ret(), Some(Data::Value(3)) ) );
+
+        assert!( matches!( vm.run_to_completion(&mut ())?, Status::Finished(Some(Data::Value(3))) ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_return_to_caller_when_call_is_last_instruction() -> R<()> {
+        let f = Symbol(0);
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadFunc(f, Func(1))
+                           , Instr::Call(f)
+                           ])
+            ,(Func(1), vec![])
+            ]);
+
+        let program = compile(&func_defs)?;
+        let mut vm = Vm::new(&program)?;
+
+        assert!( matches!( vm.run_to_completion(&mut ())?, Status::Finished(None) ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_suspend_infinite_loop_when_out_of_fuel() -> R<()> {
+        let sym = Symbol(0);
+        let f = Symbol(1);
+        let top = Label(0);
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadFunc(f, Func(1))
+                           , Instr::Call(f)
+                           , Instr::Return(sym)
+                           ])
+            ,(Func(1), vec![ Instr::Label(top)
+                           , Instr::Jump(top)
+                           ])
+            ]);
+
+        let program = compile(&func_defs)?;
+        let mut vm = Vm::new(&program)?;
+        vm.set_fuel(Some(10));
+
+        assert!( matches!( vm.run_to_completion(&mut ())?, Status::OutOfFuel ) );
+        assert_eq!( vm.fuel(), Some(0) );
+        assert_eq!( vm.fuel_consumed().get(&Func(0)), Some(&2) );
+        assert_eq!( vm.fuel_consumed().get(&Func(1)), Some(&8) );
+
+        // NOTE:  Suspension is not terminal; the host can keep going after refueling.
+        assert!( matches!( vm.step(&mut ())?, Status::OutOfFuel ) );
+        vm.refuel(4);
+        assert!( matches!( vm.run_to_completion(&mut ())?, Status::OutOfFuel ) );
+        assert_eq!( vm.fuel_consumed().get(&Func(1)), Some(&12) );
+        assert_eq!( vm.current_function(), Func(1) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_finish_when_fuel_is_sufficient() -> R<()> {
+        let sym = Symbol(0);
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadValue(sym, 4)
+                           , Instr::Return(sym)
+                           ])
+            ]);
+
+        let program = compile(&func_defs)?;
+        let mut vm = Vm::new(&program)?;
+        vm.set_fuel(Some(2));
+
+        assert!( matches!( vm.run_to_completion(&mut ())?, Status::Finished(Some(Data::Value(4))) ) );
+        assert_eq!( vm.fuel(), Some(0) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_stack_overflow_for_runaway_recursion() -> R<()> {
+        let f = Symbol(0);
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadFunc(f, Func(1))
+                           , Instr::Call(f)
+                           ])
+            ,(Func(1), vec![ Instr::LoadFunc(f, Func(1))
+                           , Instr::Call(f)
+                           ])
+            ]);
+
+        let limits = VmLimits { max_frames: Some(8), ..VmLimits::default() };
+        let program = compile(&func_defs)?;
+        let mut vm = Vm::with_limits(&program, limits)?;
+
+        let error = vm.run_to_completion(&mut ()).unwrap_err();
+        assert!( matches!( error.downcast_ref::<VmError>(), Some(VmError::StackOverflow { func: 1, depth: 9 }) ) );
+        assert_eq!( vm.depth(), 8 );
+        Ok(())
+    }
+
+    #[test]
+    fn should_run_tail_calls_in_constant_stack() -> R<()> {
+        let n = Symbol(0);
+        let acc = Symbol(1);
+        let f = Symbol(2);
+        let end = Label(0);
+        let func_defs : HashMap<Func, Vec<Instr<usize, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadValue(n, 1000)
+                           , Instr::LoadValue(acc, 0)
+                           , Instr::PushParam(acc)
+                           , Instr::PushParam(n)
+                           , Instr::LoadFunc(f, Func(1))
+                           , Instr::Call(f)
+                           , Instr::LoadFromReturn(acc)
+                           , Instr::Return(acc)
+                           ])
+            ,(Func(1), vec![ Instr::PopParam(n)
+                           , Instr::PopParam(acc)
+                           , Instr::BranchOnTrue(end, Box::new(move |locals| Ok(matches!(locals.get(&n)?, Data::Value(0)))))
+                           , Instr::LoadFromExec(acc, Box::new(move |locals| match (locals.get(&acc)?, locals.get(&n)?) {
+                                 (Data::Value(acc), Data::Value(n)) => Ok(Data::Value(acc + n)),
+                                 _ => Ok(Data::Value(0)),
+                             }))
+                           , Instr::LoadFromExec(n, Box::new(move |locals| match locals.get(&n)? {
+                                 Data::Value(n) => Ok(Data::Value(n - 1)),
+                                 _ => Ok(Data::Value(0)),
+                             }))
+                           , Instr::PushParam(acc)
+                           , Instr::PushParam(n)
+                           , Instr::LoadFunc(f, Func(1))
+                           , Instr::TailCall(f)
+                           , Instr::Label(end)
+                           , Instr::Return(acc)
+                           ])
+            ]);
+
+        let limits = VmLimits { max_frames: Some(1), ..VmLimits::default() };
+        let program = compile(&func_defs)?;
+        let mut vm = Vm::with_limits(&program, limits)?;
+
+        assert!( matches!( vm.run_to_completion(&mut ())?, Status::Finished(Some(Data::Value(500500))) ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_return_native_tail_call_to_caller() -> R<()> {
+        let sym = Symbol(0);
+        let func_defs : HashMap<Func, Vec<Instr<usize, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadFunc(sym, Func(1))
+                           , Instr::Call(sym)
+                           , Instr::LoadFromReturn(sym)
+                           , Instr::Return(sym)
+                           ])
+            ,(Func(1), vec![ Instr::LoadNative(sym, Native(0))
+                           , Instr::TailCall(sym)
+                           , Instr::LoadValue(sym, 1)
+                           , Instr::Return(sym)
+                           ])
+            ]);
+
+        let mut program = compile(&func_defs)?;
+        program.register_native(Native(0), |_, _| Ok(Data::Value(7)));
+        let mut vm = Vm::new(&program)?;
+
+        assert!( matches!( vm.run_to_completion(&mut ())?, Status::Finished(Some(Data::Value(7))) ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_resume_coroutine_until_finished() -> R<()> {
+        let gen = Symbol(0);
+        let a = Symbol(1);
+        let b = Symbol(2);
+        let c = Symbol(3);
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadFunc(gen, Func(1))
+                           , Instr::Resume(gen)
+                           , Instr::LoadFromReturn(a)
+                           , Instr::Resume(gen)
+                           , Instr::LoadFromReturn(b)
+                           , Instr::Resume(gen)
+                           , Instr::LoadFromReturn(c)
+                           , Instr::Resume(gen)
+                           ])
+            ,(Func(1), vec![ Instr::LoadValue(a, 1)
+                           , Instr::Yield(a)
+                           , Instr::LoadValue(b, 2)
+                           , Instr::Yield(b)
+                           , Instr::Return(a)
+                           ])
+            ]);
+
+        let program = compile(&func_defs)?;
+        let mut vm = Vm::new(&program)?;
+
+        let error = vm.run_to_completion(&mut ()).unwrap_err();
+        assert!( matches!( error.downcast_ref::<VmError>(), Some(VmError::ResumeOfFinishedCoroutine { func: 0, sym: 0 }) ) );
+        assert!( matches!( vm.locals().get(&a)?, Data::Value(1) ) );
+        assert!( matches!( vm.locals().get(&b)?, Data::Value(2) ) );
+        assert!( matches!( vm.locals().get(&c)?, Data::Value(1) ) );
+        assert!( matches!( vm.locals().get(&gen)?, Data::Coroutine(coroutine) if coroutine.is_finished() ) );
+        assert_eq!( vm.depth(), 0 );
+        Ok(())
+    }
+
+    #[test]
+    fn should_keep_coroutine_locals_between_resumes() -> R<()> {
+        let gen = Symbol(0);
+        let total = Symbol(1);
+        let n = Symbol(3);
+        let top = Label(0);
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadValue(total, 0)
+                           , Instr::LoadFunc(gen, Func(1))
+                           , Instr::Resume(gen)
+                           , Instr::Resume(gen)
+                           , Instr::Resume(gen)
+                           , Instr::LoadFromReturn(total)
+                           , Instr::Return(total)
+                           ])
+            ,(Func(1), vec![ Instr::LoadValue(n, 0)
+                           , Instr::Label(top)
+                           , Instr::LoadFromExec(n, Box::new(move |locals| match locals.get(&n)? {
+                                 Data::Value(n) => Ok(Data::Value(n + 1)),
+                                 _ => Ok(Data::Value(0)),
+                             }))
+                           , Instr::Yield(n)
+                           , Instr::Jump(top)
+                           ])
+            ]);
+
+        let program = compile(&func_defs)?;
+        let mut vm = Vm::new(&program)?;
+
+        assert!( matches!( vm.run_to_completion(&mut ())?, Status::Finished(Some(Data::Value(3))) ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_keep_coroutine_try_regions_between_resumes() -> R<()> {
+        let gen = Symbol(0);
+        let a = Symbol(1);
+        let b = Symbol(2);
+        let c = Symbol(3);
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadFunc(gen, Func(1))
+                           , Instr::Resume(gen)
+                           , Instr::LoadFromReturn(a)
+                           , Instr::Resume(gen)
+                           , Instr::LoadFromReturn(b)
+                           , Instr::Resume(gen)
+                           , Instr::LoadFromReturn(c)
+                           , Instr::Return(c)
+                           ])
+            ,(Func(1), vec![ Instr::Try(Label(0))
+                           , Instr::LoadValue(a, 1)
+                           , Instr::Yield(a)
+                           , Instr::Throw(a)
+                           , Instr::EndTry
+                           , Instr::Label(Label(0))
+                           , Instr::Catch(b)
+                           , Instr::Try(Label(1))
+                           , Instr::Yield(b)
+                           , Instr::EndTry
+                           , Instr::LoadValue(c, 3)
+                           , Instr::Return(c)
+                           , Instr::Label(Label(1))
+                           ])
+            ]);
+
+        let program = compile(&func_defs)?;
+        let mut vm = Vm::new(&program)?;
+
+        assert!( matches!( vm.run_to_completion(&mut ())?, Status::Finished(Some(Data::Value(3))) ) );
+        assert!( matches!( vm.locals().get(&a)?, Data::Value(1) ) );
+        assert!( matches!( vm.locals().get(&b)?, Data::Value(1) ) );
+        assert!( matches!( vm.locals().get(&gen)?, Data::Coroutine(coroutine) if coroutine.is_finished() ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_fail_to_yield_outside_coroutine() -> R<()> {
+        let sym = Symbol(0);
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadFunc(sym, Func(1))
+                           , Instr::Call(sym)
+                           ])
+            ,(Func(1), vec![ Instr::LoadValue(sym, 1)
+                           , Instr::Yield(sym)
+                           ])
+            ]);
+
+        let program = compile(&func_defs)?;
+        let mut vm = Vm::new(&program)?;
+
+        let error = vm.run_to_completion(&mut ()).unwrap_err();
+        assert!( matches!( error.downcast_ref::<VmError>(), Some(VmError::YieldOutsideCoroutine { func: 1 }) ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_suspend_on_pending_sys_call_until_completed() -> R<()> {
+        let sym = Symbol(0);
+        let func_defs : HashMap<Func, Vec<Instr<u8, u8>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadFromPollSysCall(sym, Box::new(|_, polls| { *polls += 1; Ok(Poll::Pending) }))
+                           , Instr::LoadFromPollSysCall(sym, Box::new(move |locals, polls| match locals.get(&sym)? {
+                                 Data::Value(x) => { *polls += 1; Ok(Poll::Ready(Data::Value(x + 1))) },
+                                 _ => Ok(Poll::Pending),
+                             }))
+                           , Instr::Return(sym)
+                           ])
+            ]);
+
+        let program = compile(&func_defs)?;
+        let mut vm = Vm::new(&program)?;
+        let mut polls = 0;
+
+        assert!( matches!( vm.run_to_completion(&mut polls)?, Status::Pending ) );
+        assert!( matches!( vm.step(&mut polls)?, Status::Pending ) );
+        assert!( vm.is_pending() );
+        assert_eq!( polls, 1 );
+
+        vm.complete(Data::Value(4))?;
+        assert!( !vm.is_pending() );
+        assert!( matches!( vm.run_to_completion(&mut polls)?, Status::Finished(Some(Data::Value(5))) ) );
+        assert_eq!( polls, 2 );
+
+        let error = vm.complete(Data::Value(0)).unwrap_err();
+        assert!( matches!( error.downcast_ref::<VmError>(), Some(VmError::NoPendingSysCall { func: 0 }) ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_param_stack_overflow() -> R<()> {
+        let sym = Symbol(0);
+        let top = Label(0);
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadValue(sym, 1)
+                           , Instr::Label(top)
+                           , Instr::PushParam(sym)
+                           , Instr::Jump(top)
+                           ])
+            ]);
+
+        let limits = VmLimits { max_params: Some(3), ..VmLimits::default() };
+        let program = compile(&func_defs)?;
+        let mut vm = Vm::with_limits(&program, limits)?;
+
+        let error = vm.run_to_completion(&mut ()).unwrap_err();
+        assert!( matches!( error.downcast_ref::<VmError>(), Some(VmError::ParamStackOverflow { func: 0, depth: 4 }) ) );
+        assert_eq!( vm.params().len(), 3 );
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_param_stack_overflow_from_native() -> R<()> {
+        let f = Symbol(0);
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadNative(f, Native(0))
+                           , Instr::Call(f)
+                           ])
+            ]);
+
+        let limits = VmLimits { max_params: Some(3), ..VmLimits::default() };
+        let mut program = compile(&func_defs)?;
+        program.register_native(Native(0), |params, _| {
+            for value in 0..5 {
+                params.push(Data::Value(value));
+            }
+            Ok(Data::Value(0))
+        });
+        let mut vm = Vm::with_limits(&program, limits)?;
+
+        let error = vm.run_to_completion(&mut ()).unwrap_err();
+        assert!( matches!( error.downcast_ref::<VmError>(), Some(VmError::ParamStackOverflow { func: 0, depth: 5 }) ) );
+        assert_eq!( vm.params().len(), 3 );
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_locals_overflow() -> R<()> {
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadValue(Symbol(0), 1)
+                           , Instr::LoadValue(Symbol(0), 2)
+                           , Instr::LoadValue(Symbol(1), 3)
+                           , Instr::LoadValue(Symbol(2), 4)
+                           ])
+            ]);
+
+        let limits = VmLimits { max_locals: Some(2), ..VmLimits::default() };
+        let program = compile(&func_defs)?;
+        let mut vm = Vm::with_limits(&program, limits)?;
+
+        let error = vm.run_to_completion(&mut ()).unwrap_err();
+        assert!( matches!( error.downcast_ref::<VmError>(), Some(VmError::LocalsOverflow { func: 0, count: 3 }) ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_keep_symbols_only_closures_know_about() -> R<()> {
+        let hidden = Symbol(9);
+        let sym = Symbol(0);
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::SysCall(Box::new(move |locals, _| locals.set(&hidden, Data::Value(6))))
+                           , Instr::LoadFromExec(sym, Box::new(move |locals| locals.get(&hidden)))
+                           , Instr::Return(sym)
+                           ])
+            ]);
+
+        let program = compile(&func_defs)?;
+        let mut vm = Vm::new(&program)?;
+
+        assert!( matches!( vm.run_to_completion(&mut ())?, Status::Finished(Some(Data::Value(6))) ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_unwind_thrown_data_to_handler_in_caller() -> R<()> {
+        let sym = Symbol(0);
+        let f = Symbol(1);
+        let handler = Label(0);
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::Try(handler)
+                           , Instr::LoadFunc(f, Func(1))
+                           , Instr::Call(f)
+                           , Instr::EndTry
+                           , Instr::LoadValue(sym, 0)
+                           , Instr::Return(sym)
+                           , Instr::Label(handler)
+                           , Instr::Catch(sym)
+                           , Instr::Return(sym)
+                           ])
+            ,(Func(1), vec![ Instr::LoadValue(sym, 1)
+                           , Instr::PushParam(sym)
+                           , Instr::LoadFunc(f, Func(2))
+                           , Instr::Call(f)
+                           ])
+            ,(Func(2), vec![ Instr::LoadValue(sym, 9)
+                           , Instr::Throw(sym)
+                           ])
+            ]);
+
+        let program = compile(&func_defs)?;
+        let mut vm = Vm::new(&program)?;
+
+        assert!( matches!( vm.run_to_completion(&mut ())?, Status::Finished(Some(Data::Value(9))) ) );
+        assert!( vm.params().is_empty() );
+        assert!( vm.exception().is_none() );
+        Ok(())
+    }
+
+    #[test]
+    fn should_catch_converted_host_errors() -> R<()> {
+        let sym = Symbol(0);
+        let handler = Label(0);
+        let func_defs : HashMap<Func, Vec<Instr<String, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::Try(handler)
+                           , Instr::SysCall(Box::new(|_, _| Err("disk on fire".into())))
+                           , Instr::EndTry
+                           , Instr::Label(handler)
+                           , Instr::Catch(sym)
+                           , Instr::Return(sym)
+                           ])
+            ]);
+
+        let program = compile(&func_defs)?;
+        let mut vm = Vm::new(&program)?;
+        vm.catch_errors(|error| Data::Value(error.to_string()));
+
+        assert!( matches!( vm.run_to_completion(&mut ())?, Status::Finished(Some(Data::Value(m))) if m == "disk on fire" ) );
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_catch_host_errors_without_converter() -> R<()> {
+        let handler = Label(0);
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::Try(handler)
+                           , Instr::SysCall(Box::new(|_, _| Err("boom".into())))
+                           , Instr::Label(handler)
+                           ])
+            ]);
+
+        let program = compile(&func_defs)?;
+        let mut vm = Vm::new(&program)?;
+
+        assert_eq!( vm.run_to_completion(&mut ()).unwrap_err().to_string(), "boom" );
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_uncaught_exception() -> R<()> {
+        let sym = Symbol(0);
+        let handler = Label(0);
+        let f = Symbol(1);
+        let func_defs : HashMap<Func, Vec<Instr<u8, ()>>> = HashMap::from(
+            [(Func(0), vec![ Instr::LoadFunc(f, Func(1))
+                           , Instr::Call(f)
+                           , Instr::LoadValue(sym, 3)
+                           , Instr::Throw(sym)
+                           ])
+            // NOTE:  The try region is discarded when its function returns.
+            ,(Func(1), vec![ Instr::Try(handler)
+                           , Instr::Label(handler)
+                           ])
+            ]);
+
+        let program = compile(&func_defs)?;
+        let mut vm = Vm::new(&program)?;
+
+        let error = vm.run_to_completion(&mut ()).unwrap_err();
+
+        assert!( matches!( error.downcast_ref::<VmError>(), Some(VmError::UncaughtException { func: 0 }) ) );
+        assert!( matches!( vm.exception(), Some(Data::Value(3)) ) );
+        Ok(())
+    }
+}